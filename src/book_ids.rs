@@ -0,0 +1,230 @@
+use std::{collections::HashSet, path::Path};
+
+use anyhow::Context;
+use log::debug;
+use reqwest::Url;
+
+use crate::error::{OrlyError, Result};
+
+/// Extract the book id from a bare id, an ISBN-10/13 with or without hyphens, or a book or
+/// chapter url like `https://learning.oreilly.com/library/view/title/9781492000000/ch01.html`.
+///
+/// ISBN-10 is converted to ISBN-13, which is what O'Reilly uses as the book id, but only when
+/// it is clearly an ISBN: hyphenated, with an `X` check digit or prefixed with `isbn:`. Other
+/// ids are kept as given, 10 digit book ids may pass the ISBN-10 checksum by chance.
+pub fn parse_book_id(input: &str) -> Result<String> {
+    let input = input.trim();
+    let invalid = || OrlyError::InvalidBookId(input.to_string());
+
+    if input.contains("://") {
+        let url = Url::parse(input).map_err(|_| invalid())?;
+        return id_from_url(&url).ok_or_else(invalid);
+    }
+
+    let (prefixed, id) = match input.get(..5) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("isbn:") => (true, input[5..].trim()),
+        _ => (false, input),
+    };
+    let normalized = id
+        .chars()
+        .filter(|c| *c != '-' && *c != ' ')
+        .collect::<String>()
+        .to_ascii_uppercase();
+
+    let explicit_isbn = prefixed || id.contains(['-', ' ']) || normalized.ends_with('X');
+    if explicit_isbn && is_isbn10(&normalized) {
+        return Ok(isbn10_to_isbn13(&normalized));
+    }
+    if !normalized.is_empty() && normalized.chars().all(|c| c.is_ascii_digit()) {
+        return Ok(normalized);
+    }
+
+    Err(invalid())
+}
+
+/// Book id in the path of a `/library/view/<title>/<id>/...` or `/api/v1/book/<id>/...` url
+fn id_from_url(url: &Url) -> Option<String> {
+    let segments = url.path_segments()?.collect::<Vec<_>>();
+    let position = segments
+        .windows(2)
+        .position(|pair| pair == ["library", "view"])
+        .map(|position| position + 3)
+        .or_else(|| {
+            segments
+                .iter()
+                .position(|segment| *segment == "book")
+                .map(|position| position + 1)
+        })?;
+
+    segments
+        .get(position)
+        .filter(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()))
+        .map(|id| id.to_string())
+}
+
+fn is_isbn10(isbn: &str) -> bool {
+    let bytes = isbn.as_bytes();
+    if bytes.len() != 10
+        || !bytes[..9].iter().all(u8::is_ascii_digit)
+        || !(bytes[9].is_ascii_digit() || bytes[9] == b'X')
+    {
+        return false;
+    }
+
+    let sum = bytes
+        .iter()
+        .enumerate()
+        .map(|(i, b)| {
+            let value = if *b == b'X' { 10 } else { (b - b'0') as usize };
+            value * (10 - i)
+        })
+        .sum::<usize>();
+    sum % 11 == 0
+}
+
+fn isbn10_to_isbn13(isbn: &str) -> String {
+    let digits = format!("978{}", &isbn[..9]);
+    let sum = digits
+        .bytes()
+        .enumerate()
+        .map(|(i, b)| (b - b'0') as usize * if i % 2 == 0 { 1 } else { 3 })
+        .sum::<usize>();
+    format!("{}{}", digits, (10 - sum % 10) % 10)
+}
+
+/// Read book ids, isbns or urls from a text file with one entry per line, or from the first
+/// column of a csv file. Empty lines and `#` comments are skipped, and so is a csv header.
+pub fn read_book_ids(path: &Path) -> Result<Vec<String>> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+    let csv = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+
+    let mut ids = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = match line.find(" #") {
+            Some(comment) => &line[..comment],
+            None => line,
+        }
+        .trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let entry = if csv {
+            line.split(',')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .trim_matches('"')
+        } else {
+            line
+        };
+
+        match parse_book_id(entry) {
+            Ok(id) => ids.push(id),
+            Err(_) if csv && number == 0 => debug!("Skipping csv header {:?}", line),
+            Err(err) => {
+                return Err(OrlyError::InvalidBookId(format!(
+                    "{} on line {} of {:?}",
+                    match err {
+                        OrlyError::InvalidBookId(entry) => entry,
+                        err => err.to_string(),
+                    },
+                    number + 1,
+                    path
+                )))
+            }
+        }
+    }
+
+    Ok(ids)
+}
+
+/// Remove repeated ids, keeping the first occurrence
+pub fn dedup_book_ids(ids: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let total = ids.len();
+    let unique = ids
+        .into_iter()
+        .filter(|id| seen.insert(id.clone()))
+        .collect::<Vec<_>>();
+    if unique.len() != total {
+        debug!("Skipping {} duplicate books", total - unique.len());
+    }
+    unique
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_ids_are_kept() {
+        assert_eq!(parse_book_id("9781492000000").unwrap(), "9781492000000");
+        assert_eq!(parse_book_id(" 1234 ").unwrap(), "1234");
+        // Passes the ISBN-10 checksum, but is not written as an ISBN
+        assert!(is_isbn10("0596520689"));
+        assert_eq!(parse_book_id("0596520689").unwrap(), "0596520689");
+    }
+
+    #[test]
+    fn explicit_isbns_are_converted() {
+        assert_eq!(parse_book_id("0-596-52068-9").unwrap(), "9780596520687");
+        assert_eq!(parse_book_id("0 596 52068 9").unwrap(), "9780596520687");
+        assert_eq!(parse_book_id("isbn:0596520689").unwrap(), "9780596520687");
+        assert_eq!(parse_book_id("ISBN: 0596520689").unwrap(), "9780596520687");
+        assert_eq!(parse_book_id("080442957x").unwrap(), "9780804429573");
+        assert_eq!(parse_book_id("978-0-596-52068-7").unwrap(), "9780596520687");
+        assert_eq!(
+            parse_book_id("isbn:9780596520687").unwrap(),
+            "9780596520687"
+        );
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            parse_book_id(
+                "https://learning.oreilly.com/library/view/title/9781492000000/ch01.html"
+            )
+            .unwrap(),
+            "9781492000000"
+        );
+        assert_eq!(
+            parse_book_id("https://learning.oreilly.com/api/v1/book/0596520689/").unwrap(),
+            "0596520689"
+        );
+        assert!(parse_book_id("https://learning.oreilly.com/library/view/title/").is_err());
+    }
+
+    #[test]
+    fn invalid_ids() {
+        for input in ["", "isbn:", "abc", "12a4", "0-596-52068-X1"] {
+            assert!(
+                matches!(parse_book_id(input), Err(OrlyError::InvalidBookId(_))),
+                "{:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn book_id_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let txt = dir.path().join("books.txt");
+        std::fs::write(&txt, "# reading list\n1234\n\n0-596-52068-9 # isbn\n1234\n").unwrap();
+        let ids = read_book_ids(&txt).unwrap();
+        assert_eq!(ids, ["1234", "9780596520687", "1234"]);
+        assert_eq!(dedup_book_ids(ids), ["1234", "9780596520687"]);
+
+        let csv = dir.path().join("books.csv");
+        std::fs::write(&csv, "id,title\n\"5678\",Title\n").unwrap();
+        assert_eq!(read_book_ids(&csv).unwrap(), ["5678"]);
+
+        std::fs::write(&txt, "1234\nnot a book\n").unwrap();
+        let err = read_book_ids(&txt).unwrap_err().to_string();
+        assert!(err.contains("line 2"), "{}", err);
+    }
+}
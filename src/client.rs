@@ -1,25 +1,34 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, future::Future, path::Path, sync::Arc, time::Duration};
 
 use bytes::Bytes;
 use chrono::{DateTime, Local, NaiveDate};
 use futures::stream::{self, StreamExt};
 
 use anyhow::Context;
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use reqwest::{
     header::{
-        HeaderMap, HeaderValue, ACCEPT, ACCEPT_ENCODING, COOKIE, UPGRADE_INSECURE_REQUESTS,
-        USER_AGENT,
+        HeaderMap, HeaderValue, ACCEPT, ACCEPT_ENCODING, UPGRADE_INSECURE_REQUESTS, USER_AGENT,
     },
-    Client, ClientBuilder, Url,
+    Client, ClientBuilder, RequestBuilder, StatusCode, Url,
 };
 
+use reqwest_cookie_store::CookieStoreMutex;
+use tokio_util::sync::CancellationToken;
+
 use crate::{
+    cookies::{self, ImportedCookie},
+    endpoints::Endpoints,
     error::{OrlyError, Result},
+    http::{
+        self, cache::Cache, limiter::Limiter, retry::RetryPolicy, transport::Transport, Response,
+    },
     models::{
         BillingInfo, Book, Chapter, ChapterMeta, ChaptersResponse, Credentials, LoginLookup,
         TocElement,
     },
+    progress::{Phase, ProgressCallback, Tracker},
+    redact::Redacted,
 };
 
 pub struct Authenticated;
@@ -37,29 +46,199 @@ impl AuthState for Unauthenticated {}
 
 pub struct OreillyClient<S: AuthState> {
     client: Client,
-    base_url: Url,
+    cookies: Arc<CookieStoreMutex>,
+    endpoints: Endpoints,
     marker: std::marker::PhantomData<S>,
     concurrent_requests: usize,
+    retry_policy: RetryPolicy,
+    limiter: Arc<Limiter>,
+    cache: Option<Arc<Cache>>,
+    transport: Transport,
+    /// Email and password to sign in again with when the session expires
+    credentials: Option<(String, String)>,
+    /// Number of times the session was renewed
+    session_renewals: Arc<tokio::sync::Mutex<u32>>,
+    progress: Option<ProgressCallback>,
+    cancellation: CancellationToken,
 }
 
 impl<S: AuthState> OreillyClient<S> {
-    fn make_url(&self, endpoint: &str) -> Result<Url> {
-        Ok(self
-            .base_url
-            .join(endpoint)
-            .with_context(|| format!("invalid endpoint: {}", endpoint))?)
+    pub fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    pub fn progress(&self) -> Option<&ProgressCallback> {
+        self.progress.as_ref()
+    }
+
+    /// Save the cookie store to a file so that the session can be restored with
+    /// [`OreillyClient::session_auth`] on the next run
+    pub fn save_session(&self, path: &Path) -> Result<()> {
+        let store = self.cookies.lock().unwrap();
+        cookies::save_session(&store, path)?;
+        debug!("Session saved to {:?}", path);
+        Ok(())
+    }
+
+    /// Sign in with email and password, the session cookies end up in the cookie store
+    async fn login(&self, email: &str, password: &str) -> Result<()> {
+        let mut map = HashMap::new();
+        map.insert("email", email);
+
+        info!("Checking if password login is possible");
+        let response = self
+            .execute_private(self.client.post(self.endpoints.login_lookup()?).json(&map))
+            .await?;
+
+        debug!("Email lookup response: {:#?}", Redacted(&response));
+
+        let login_lookup = response.json::<LoginLookup>()?;
+
+        if !login_lookup.password_login_allowed {
+            return Err(crate::error::OrlyError::PasswordLoginUnsupported(
+                email.to_string(),
+            ));
+        }
+
+        info!("Logging into Safari Books Online...");
+
+        map.insert("password", password);
+
+        let response = self
+            .execute_private(
+                self.client
+                    .post(self.endpoints.login()?)
+                    .json(&map)
+                    .basic_auth(
+                        "532409",
+                        Some("ce1e4a0d4f726a27a6dbad88e4732c5f7dee15e36e15899971b5d5e7"),
+                    ),
+            )
+            .await
+            .map_err(|err| {
+                OrlyError::AuthenticationFailed(format!(
+                    "Login request failed, make sure your email and password are correct: {}",
+                    err
+                ))
+            })?;
+
+        debug!("Auth response: {:#?}", Redacted(&response));
+
+        let credentials = response.json::<Credentials>()?;
+
+        if !credentials.logged_in {
+            return Err(OrlyError::AuthenticationFailed(
+                "Expected to be logged in".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Run the future unless the client is cancelled first
+    async fn cancellable<F: Future>(&self, future: F) -> Result<F::Output> {
+        tokio::select! {
+            biased;
+            _ = self.cancellation.cancelled() => Err(OrlyError::Cancelled),
+            output = future => Ok(output),
+        }
+    }
+
+    /// Send the request and read the whole response body, retrying transient failures
+    /// according to the retry policy. Non-success responses are returned as errors.
+    async fn execute(&self, request: RequestBuilder) -> Result<Response> {
+        self.execute_with(request, false).await
+    }
+
+    /// Like [`Self::execute`] for sign in and account requests, their responses are never
+    /// recorded
+    async fn execute_private(&self, request: RequestBuilder) -> Result<Response> {
+        self.execute_with(request, true).await
+    }
+
+    async fn execute_with(&self, request: RequestBuilder, private: bool) -> Result<Response> {
+        let request = request.build()?;
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            // Requests without a streaming body can always be cloned
+            let current = request
+                .try_clone()
+                .context("request body can not be cloned")?;
+
+            let permit = self.cancellable(self.limiter.acquire()).await?;
+            let result = self
+                .cancellable(http::send(&self.client, &self.transport, current, private))
+                .await?;
+            drop(permit);
+
+            let failure = match result {
+                Ok(response) => {
+                    self.limiter.record_success();
+                    if attempt > 1 {
+                        debug!(
+                            "Request to {} succeeded after {} attempts",
+                            Redacted(request.url()),
+                            attempt
+                        );
+                    }
+                    return Ok(response);
+                }
+                Err(failure) => failure,
+            };
+
+            if failure.error.is_throttled() {
+                self.limiter.record_throttled();
+            }
+
+            if !failure.error.is_transient()
+                || !self.retry_policy.should_retry(request.method(), attempt)
+            {
+                if attempt > 1 {
+                    error!(
+                        "Giving up on {} after {} attempts: {}",
+                        Redacted(request.url()),
+                        attempt,
+                        failure.error
+                    );
+                }
+                return Err(failure.error);
+            }
+
+            let delay = self.retry_policy.delay(attempt, failure.retry_after);
+            warn!(
+                "Request to {} failed (attempt {}/{}): {}. Retrying in {:.1}s",
+                Redacted(request.url()),
+                attempt,
+                self.retry_policy.max_attempts(),
+                failure.error,
+                delay.as_secs_f32()
+            );
+            self.cancellable(tokio::time::sleep(delay)).await?;
+        }
     }
 }
 
 impl Default for OreillyClient<Unauthenticated> {
     fn default() -> Self {
+        let cookies = Arc::new(CookieStoreMutex::default());
         Self {
-            client: Self::default_client().build().expect("to build the client"),
-            base_url: "https://learning.oreilly.com"
-                .parse()
-                .expect("correct base url"),
+            client: Self::default_client(cookies.clone())
+                .build()
+                .expect("to build the client"),
+            cookies,
+            endpoints: Default::default(),
             marker: std::marker::PhantomData,
             concurrent_requests: 20,
+            retry_policy: Default::default(),
+            limiter: Arc::new(Limiter::new(20, None)),
+            cache: None,
+            transport: Default::default(),
+            credentials: None,
+            session_renewals: Default::default(),
+            progress: None,
+            cancellation: Default::default(),
         }
     }
 }
@@ -68,11 +247,93 @@ impl OreillyClient<Unauthenticated> {
     pub fn new(concurrent_requests: usize) -> Self {
         Self {
             concurrent_requests,
+            limiter: Arc::new(Limiter::new(concurrent_requests, None)),
             ..Default::default()
         }
     }
 
-    fn default_client() -> ClientBuilder {
+    /// Cap the number of requests per second across all downloads
+    pub fn with_rate_limit(self, requests_per_second: f64) -> Self {
+        Self {
+            limiter: Arc::new(Limiter::new(
+                self.concurrent_requests,
+                Some(requests_per_second),
+            )),
+            ..self
+        }
+    }
+
+    /// Use custom hosts instead of the public O'Reilly ones
+    pub fn with_endpoints(self, endpoints: Endpoints) -> Self {
+        Self { endpoints, ..self }
+    }
+
+    pub fn with_retry_policy(self, retry_policy: RetryPolicy) -> Self {
+        Self {
+            retry_policy,
+            ..self
+        }
+    }
+
+    /// Store downloaded chapters, images and stylesheets on disk so that they are not
+    /// downloaded again on the next run
+    pub fn with_cache(self, cache: Cache) -> Self {
+        Self {
+            cache: Some(Arc::new(cache)),
+            ..self
+        }
+    }
+
+    /// Report download progress of chapters and, unless the builder has its own callback,
+    /// of the files downloaded by [`crate::epub::builder::EpubBuilder`]
+    pub fn with_progress(self, progress: ProgressCallback) -> Self {
+        Self {
+            progress: Some(progress),
+            ..self
+        }
+    }
+
+    /// Stop sending requests once the token is cancelled, requests in flight are aborted.
+    /// Files that were already downloaded are kept in the cache.
+    pub fn with_cancellation(self, cancellation: CancellationToken) -> Self {
+        Self {
+            cancellation,
+            ..self
+        }
+    }
+
+    /// Record every response to a fixture directory or replay a recorded run offline
+    pub fn with_transport(self, transport: Transport) -> Self {
+        Self { transport, ..self }
+    }
+
+    fn with_credentials(self, credentials: Option<(&str, &str)>) -> Self {
+        Self {
+            credentials: credentials
+                .map(|(email, password)| (email.to_string(), password.to_string())),
+            ..self
+        }
+    }
+
+    fn into_authenticated(self) -> OreillyClient<Authenticated> {
+        OreillyClient {
+            client: self.client,
+            cookies: self.cookies,
+            endpoints: self.endpoints,
+            concurrent_requests: self.concurrent_requests,
+            retry_policy: self.retry_policy,
+            limiter: self.limiter,
+            cache: self.cache,
+            transport: self.transport,
+            credentials: self.credentials,
+            session_renewals: self.session_renewals,
+            progress: self.progress,
+            cancellation: self.cancellation,
+            marker: std::marker::PhantomData,
+        }
+    }
+
+    fn default_client(cookies: Arc<CookieStoreMutex>) -> ClientBuilder {
         let mut headers = HeaderMap::new();
         headers.insert(ACCEPT, HeaderValue::from_static("*/*"));
         headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, deflate"));
@@ -83,18 +344,22 @@ impl OreillyClient<Unauthenticated> {
         );
         reqwest::Client::builder()
             .default_headers(headers)
-            .cookie_store(true)
+            .cookie_provider(cookies)
     }
 
-    async fn check_subscription(&self, client: &Client) -> Result<()> {
-        info!("Validating subscription");
-        let response = client.get(self.make_url("api/v1/")?).send().await?;
-
-        response.error_for_status_ref()?;
+    async fn check_subscription(&self) -> Result<()> {
+        if let Transport::Replay(_) = self.transport {
+            debug!("Account details are not recorded, skipping the subscription check");
+            return Ok(());
+        }
 
-        let billing = response.json::<BillingInfo>().await?;
+        info!("Validating subscription");
+        let response = self
+            .execute_private(self.client.get(self.endpoints.billing()?))
+            .await?;
 
-        trace!("Billing details: {:#?}", &billing);
+        trace!("Billing details: {}", Redacted(response.text()));
+        let billing = response.json::<BillingInfo>()?;
         let expiration = if let Some(sub_exp) = billing.subscription.cancellation_date {
             let dt = NaiveDate::parse_from_str(&sub_exp, "%Y-%m-%d")
                 .context("failed to parse subscription expiration ")?;
@@ -123,115 +388,155 @@ impl OreillyClient<Unauthenticated> {
         email: &str,
         password: &str,
     ) -> Result<OreillyClient<Authenticated>> {
-        let mut map = HashMap::new();
-        map.insert("email", email);
-
-        info!("Checking if password login is possible");
-        let response = self
-            .client
-            .post("https://api.oreilly.com/api/m/v2/auth/lookup/")
-            .json(&map)
-            .send()
-            .await?;
-
-        debug!("Email lookup response: {:#?}", response);
+        self.login(email, password).await?;
+        self.check_subscription().await?;
 
-        let login_lookup = response.json::<LoginLookup>().await?;
-
-        if !login_lookup.password_login_allowed {
-            return Err(crate::error::OrlyError::PasswordLoginUnsupported(
-                email.to_string(),
-            ));
-        }
+        Ok(self
+            .with_credentials(Some((email, password)))
+            .into_authenticated())
+    }
 
-        info!("Logging into Safari Books Online...");
+    pub async fn cookie_auth(self, cookie: &str) -> Result<OreillyClient<Authenticated>> {
+        let cookies = cookies::parse_cookie_header(cookie, &self.endpoints.cookie_domain())?;
+        self.cookies_auth(&cookies).await
+    }
 
-        map.insert("password", password);
+    /// Authenticate with cookies imported from a browser, see [`cookies::load_cookie_file`]
+    pub async fn cookies_auth(
+        self,
+        cookies: &[ImportedCookie],
+    ) -> Result<OreillyClient<Authenticated>> {
+        info!("Logging into Safari Books Online using cookies...");
 
-        let response = self
-            .client
-            .post("https://api.oreilly.com/api/v1/auth/login/")
-            .json(&map)
-            .basic_auth(
-                "532409",
-                Some("ce1e4a0d4f726a27a6dbad88e4732c5f7dee15e36e15899971b5d5e7"),
-            )
-            .send()
-            .await?;
+        cookies::insert_cookies(&mut self.cookies.lock().unwrap(), cookies)?;
+        self.check_subscription().await?;
 
-        debug!("Auth response: {:#?}", response);
+        Ok(self.into_authenticated())
+    }
 
-        if let Err(err) = response.error_for_status_ref() {
-            return Err(OrlyError::AuthenticationFailed(format!(
-                "Login request failed, make sure your email and password are correct: {}",
-                err
-            )));
+    /// Restore a session saved with [`OreillyClient::save_session`]. If the saved session is
+    /// missing or no longer valid, logs in with `credentials` when they are given, otherwise
+    /// with the result of `ask_credentials`, e.g. to only prompt for a password when needed.
+    pub async fn session_auth(
+        self,
+        path: &Path,
+        credentials: Option<(&str, &str)>,
+        ask_credentials: impl FnOnce() -> Result<Option<(String, String)>>,
+    ) -> Result<OreillyClient<Authenticated>> {
+        let mut expired = false;
+        match cookies::load_session(path) {
+            Ok(store) => {
+                info!("Restoring saved session");
+                *self.cookies.lock().unwrap() = store;
+                match self.check_subscription().await {
+                    Ok(()) => return Ok(self.with_credentials(credentials).into_authenticated()),
+                    // Logging in again will not renew the subscription
+                    Err(OrlyError::SubscriptionExpired) => {
+                        return Err(OrlyError::SubscriptionExpired)
+                    }
+                    Err(err) => warn!("Saved session is no longer valid: {}", Redacted(&err)),
+                }
+                self.cookies.lock().unwrap().clear();
+                expired = true;
+            }
+            Err(err) if path.exists() => warn!("Failed to load saved session: {}", err),
+            Err(_) => debug!("No saved session found at {:?}", path),
         }
 
-        let credentials = response.json::<Credentials>().await?;
-
-        if !credentials.logged_in {
-            return Err(OrlyError::AuthenticationFailed(
-                "Expected to be logged in".to_string(),
-            ));
+        if let Some((email, password)) = credentials {
+            return self.cred_auth(email, password).await;
         }
+        match ask_credentials()? {
+            Some((email, password)) => self.cred_auth(&email, &password).await,
+            None if expired => Err(OrlyError::SessionExpired),
+            None => Err(OrlyError::NoCredentials),
+        }
+    }
+}
 
-        self.check_subscription(&self.client).await?;
-
-        Ok(OreillyClient {
-            client: self.client,
-            base_url: self.base_url,
-            concurrent_requests: self.concurrent_requests,
-            marker: std::marker::PhantomData,
-        })
+impl OreillyClient<Authenticated> {
+    /// Like [`OreillyClient::execute`], but if the session expired in the middle of a run, signs
+    /// in again and repeats the request once
+    async fn fetch(&self, request: RequestBuilder) -> Result<Response> {
+        let repeat = request.try_clone();
+        let renewals = *self.session_renewals.lock().await;
+
+        match self.execute(request).await {
+            Err(OrlyError::Unauthorized { url, .. }) => {
+                debug!("Request to {} is unauthorized", Redacted(&url));
+                self.renew_session(renewals).await?;
+                let repeat = repeat.context("request can not be repeated")?;
+                self.execute(repeat).await.map_err(|err| match err {
+                    OrlyError::Unauthorized { .. } => OrlyError::SessionExpired,
+                    err => err,
+                })
+            }
+            result => result,
+        }
     }
 
-    pub async fn cookie_auth(self, cookie: &str) -> Result<OreillyClient<Authenticated>> {
-        info!("Logging into Safari Books Online using cookies...");
+    /// Sign in again with the saved credentials. `renewals` is the number of renewals seen
+    /// before the failed request, concurrent requests that failed with the same session wait
+    /// for a single sign in instead of signing in one after another.
+    async fn renew_session(&self, renewals: u32) -> Result<()> {
+        let mut current = self.session_renewals.lock().await;
+        if *current != renewals {
+            return Ok(());
+        }
 
-        let mut request_headers = HeaderMap::new();
-        request_headers.insert(
-            COOKIE,
-            HeaderValue::from_str(cookie).context("Invalid cookie")?,
-        );
+        let Some((email, password)) = &self.credentials else {
+            return Err(OrlyError::SessionExpired);
+        };
 
-        let client = Self::default_client()
-            .default_headers(request_headers)
-            .build()?;
-        self.check_subscription(&client).await?;
+        warn!("Session expired, signing in again");
+        self.cookies.lock().unwrap().clear();
+        self.login(email, password).await?;
+        *current += 1;
 
-        Ok(OreillyClient {
-            client,
-            base_url: self.base_url,
-            concurrent_requests: self.concurrent_requests,
-            marker: std::marker::PhantomData,
-        })
+        Ok(())
     }
-}
 
-impl OreillyClient<Authenticated> {
     pub async fn fetch_book_details(&self, book_id: &str) -> Result<Book> {
         info!("Fetching book details");
         let response = self
-            .client
-            .get(self.make_url(&format!("api/v1/book/{}/", book_id))?)
-            .send()
-            .await?;
+            .fetch(self.client.get(self.endpoints.book(book_id)?))
+            .await
+            .map_err(|err| err.with_book_id(book_id))?;
 
-        response.error_for_status_ref()?;
-        let book = response.json::<Book>().await?;
-        trace!("Book: {:#?}", &book);
+        let book = response.json::<Book>()?;
+        trace!("Book: {:#?}", Redacted(&book));
         Ok(book)
     }
 
+    /// Download all `urls`. Files that don't exist on the server are left out of the result.
     pub async fn bulk_download_bytes<'a, T: IntoIterator<Item = &'a Url>>(
         &'a self,
         urls: T,
     ) -> Result<Vec<(&'a Url, Bytes)>> {
-        let responses = stream::iter(urls.into_iter())
+        self.bulk_download_tracked(urls, &Tracker::disabled()).await
+    }
+
+    /// Like [`OreillyClient::bulk_download_bytes`], reports every downloaded file to `tracker`
+    pub(crate) async fn bulk_download_tracked<'a, T: IntoIterator<Item = &'a Url>>(
+        &'a self,
+        urls: T,
+        tracker: &Tracker<'_>,
+    ) -> Result<Vec<(&'a Url, Bytes)>> {
+        let responses = stream::iter(urls)
             .map(|url| async move {
-                let resp = self.client.get(url.clone()).send().await?.bytes().await?;
-                Ok::<(&'a Url, Bytes), OrlyError>((url, resp))
+                match self.download(url).await {
+                    Ok(resp) => {
+                        tracker.advance(resp.len());
+                        Ok(Some((url, resp)))
+                    }
+                    // A missing image or stylesheet should not fail the whole book
+                    Err(OrlyError::NotFound { .. }) => {
+                        warn!("Skipping {}, it was not found on the server", Redacted(url));
+                        tracker.advance(0);
+                        Ok(None)
+                    }
+                    Err(err) => Err(err),
+                }
             })
             .buffer_unordered(self.concurrent_requests);
 
@@ -239,23 +544,80 @@ impl OreillyClient<Authenticated> {
             .collect::<Vec<_>>()
             .await
             .into_iter()
-            .collect::<std::result::Result<Vec<_>, _>>()?;
-        Ok(responses)
+            .collect::<std::result::Result<Vec<_>, OrlyError>>()?;
+        Ok(responses.into_iter().flatten().collect())
     }
 
+    /// Download a chapter or another page that may change between runs. Unlike other files, it
+    /// is only taken from the cache if the server confirms it didn't change.
     pub async fn download_text(&self, url: Url) -> Result<String> {
-        Ok(self.client.get(url).send().await?.text().await?)
+        let bytes = self.download_with_max_age(&url, Duration::ZERO).await?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    async fn download(&self, url: &Url) -> Result<Bytes> {
+        let max_age = self
+            .cache
+            .as_ref()
+            .map(|cache| cache.max_age())
+            .unwrap_or_default();
+        self.download_with_max_age(url, max_age).await
+    }
+
+    /// Download a file going through the cache, if enabled. Cached entries are revalidated
+    /// when the server provided an `ETag` or `Last-Modified` header, otherwise they are used
+    /// as is until they are older than `max_age`.
+    async fn download_with_max_age(&self, url: &Url, max_age: Duration) -> Result<Bytes> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok(self.fetch(self.client.get(url.clone())).await?.bytes()),
+        };
+
+        let cached = cache.get(url).await;
+        let request = match &cached {
+            Some(entry) if entry.can_revalidate() => entry.revalidate(self.client.get(url.clone())),
+            Some(entry) if entry.is_fresh(max_age) => {
+                trace!("Cache hit: {}", Redacted(url));
+                return Ok(entry.body.clone());
+            }
+            _ => self.client.get(url.clone()),
+        };
+
+        let response = self.fetch(request).await?;
+
+        if let (StatusCode::NOT_MODIFIED, Some(entry)) = (response.status(), cached) {
+            trace!("Cache entry is still fresh: {}", Redacted(url));
+            return Ok(entry.body);
+        }
+
+        if let Err(err) = cache.put(url, response.headers(), response.body()).await {
+            warn!("Failed to cache {}: {}", Redacted(url), Redacted(&err));
+        }
+
+        Ok(response.bytes())
     }
 
     async fn fetch_chapters_content(
         &self,
+        book_id: &str,
         chapters_meta: Vec<ChapterMeta>,
     ) -> Result<Vec<Chapter>> {
         info!("Fetching chapter content");
+        let tracker = Tracker::start(
+            self.progress.as_ref(),
+            book_id,
+            Phase::Chapters,
+            chapters_meta.len(),
+        );
+        let tracker = &tracker;
 
-        let chapters = stream::iter(chapters_meta.into_iter())
+        let chapters = stream::iter(chapters_meta)
             .map(|meta| async move {
-                let content = self.download_text(meta.content_url.clone()).await?;
+                let content = self
+                    .download_text(meta.content_url.clone())
+                    .await
+                    .map_err(|err| err.within_book(book_id))?;
+                tracker.advance(content.len());
                 Ok::<Chapter, OrlyError>(Chapter { meta, content })
             })
             .buffer_unordered(self.concurrent_requests);
@@ -268,23 +630,23 @@ impl OreillyClient<Authenticated> {
 
         chapters.sort_by_key(|c| c.meta.position);
 
-        trace!("Chapter content: {:?}", chapters);
+        trace!("Chapter content: {:?}", Redacted(&chapters));
 
         Ok(chapters)
     }
 
     async fn fetch_chapters_meta(&self, book_id: &str) -> Result<Vec<ChapterMeta>> {
         info!("Loading chapter information");
-        let url = self
-            .make_url(&format!("api/v1/book/{}/chapter", book_id))?
-            .to_string();
+        let url = self.endpoints.chapters(book_id)?.to_string();
 
-        let response = self.client.get(url.clone()).send().await?;
-        response.error_for_status_ref()?;
+        let response = self
+            .fetch(self.client.get(url.clone()))
+            .await
+            .map_err(|err| err.within_book(book_id))?;
 
-        let first_page = response.json::<ChaptersResponse>().await?;
+        let first_page = response.json::<ChaptersResponse>()?;
 
-        trace!("First page: {:#?}", first_page);
+        trace!("First page: {:#?}", Redacted(&first_page));
 
         let total_chapters = first_page.count;
         let per_page = first_page.results.len();
@@ -298,12 +660,14 @@ impl OreillyClient<Authenticated> {
 
         let pages = stream::iter(2..=pages)
             .map(|page| {
-                let client = &self.client;
                 let url = &url;
 
                 async move {
-                    let resp = client.get(url).query(&[("page", page)]).send().await?;
-                    resp.json::<ChaptersResponse>().await
+                    let resp = self
+                        .fetch(self.client.get(url).query(&[("page", page)]))
+                        .await
+                        .map_err(|err| err.within_book(book_id))?;
+                    resp.json::<ChaptersResponse>()
                 }
             })
             .buffered(self.concurrent_requests);
@@ -321,30 +685,522 @@ impl OreillyClient<Authenticated> {
             chapter.position = position;
         }
 
-        trace!("Chapters meta: {:?}", chapters);
+        trace!("Chapters meta: {:?}", Redacted(&chapters));
         info!("Finished downloading chapter meta");
 
         Ok(chapters)
     }
 
     pub async fn fetch_book_chapters(&self, book_id: &str) -> Result<Vec<Chapter>> {
-        let meta = self.fetch_chapters_meta(book_id).await?;
-        self.fetch_chapters_content(meta).await
+        let meta = self.fetch_book_chapters_meta(book_id).await?;
+        self.fetch_book_chapters_content(book_id, meta).await
+    }
+
+    /// List the chapters of the book without downloading their content
+    pub async fn fetch_book_chapters_meta(&self, book_id: &str) -> Result<Vec<ChapterMeta>> {
+        self.fetch_chapters_meta(book_id).await
+    }
+
+    /// Download the content of the chapters listed by [`Self::fetch_book_chapters_meta`]
+    pub async fn fetch_book_chapters_content(
+        &self,
+        book_id: &str,
+        meta: Vec<ChapterMeta>,
+    ) -> Result<Vec<Chapter>> {
+        self.fetch_chapters_content(book_id, meta).await
     }
 
     pub async fn fetch_toc(&self, book_id: &str) -> Result<Vec<TocElement>> {
         info!("Loading table of contents");
 
         let response = self
-            .client
-            .get(self.make_url(&format!("api/v1/book/{}/toc", book_id))?)
-            .send()
-            .await?;
-
-        response.error_for_status_ref()?;
+            .fetch(self.client.get(self.endpoints.toc(book_id)?))
+            .await
+            .map_err(|err| err.within_book(book_id))?;
 
-        let toc = response.json::<Vec<TocElement>>().await?;
-        trace!("Table of contants: {:#?}", toc);
+        let toc = response.json::<Vec<TocElement>>()?;
+        trace!("Table of contants: {:#?}", Redacted(&toc));
         Ok(toc)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Server whose session expires after every request. Returns its url and the number of
+    /// sign ins.
+    async fn expiring_server() -> (Url, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let logins = Arc::new(AtomicUsize::new(0));
+        let signed_in = Arc::new(std::sync::Mutex::new(false));
+
+        let counter = logins.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![0; 8192];
+                let len = stream.read(&mut request).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..len]).into_owned();
+                let path = request.split(' ').nth(1).unwrap_or_default().to_string();
+
+                let (status, body) = match path.as_str() {
+                    "/api/m/v2/auth/lookup/" => ("200 OK", r#"{"password_login_allowed": true}"#),
+                    "/api/v1/auth/login/" => {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        *signed_in.lock().unwrap() = true;
+                        ("200 OK", r#"{"logged_in": true}"#)
+                    }
+                    "/api/v1/" => ("200 OK", r#"{"subscription": {}, "trial": {}}"#),
+                    _ if std::mem::take(&mut *signed_in.lock().unwrap()) => ("200 OK", "[]"),
+                    _ => ("401 Unauthorized", "{}"),
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url.parse().unwrap(), logins)
+    }
+
+    #[tokio::test]
+    async fn session_is_renewed_every_time_it_expires() {
+        let (url, logins) = expiring_server().await;
+        let endpoints = Endpoints::default()
+            .with_learning_url(url.clone())
+            .with_auth_url(url);
+        let client = OreillyClient::default()
+            .with_endpoints(endpoints)
+            .with_retry_policy(RetryPolicy::none())
+            .cred_auth("email@example.com", "password")
+            .await
+            .unwrap();
+
+        for _ in 0..3 {
+            client.fetch_toc("1234").await.unwrap();
+        }
+        // The first request uses the initial session, the others sign in again
+        assert_eq!(logins.load(Ordering::SeqCst), 3);
+    }
+
+    /// Server answering every request after a short delay. Returns its url and the highest
+    /// number of requests it answered at the same time.
+    async fn slow_server() -> (Url, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+        let max = max_in_flight.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let in_flight = in_flight.clone();
+                let max = max.clone();
+                tokio::spawn(async move {
+                    let mut request = vec![0; 8192];
+                    let _ = stream.read(&mut request).await;
+                    let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max.fetch_max(current, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+
+                    let body = r#"{"subscription": {}, "trial": {}}"#;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        (url.parse().unwrap(), max_in_flight)
+    }
+
+    #[tokio::test]
+    async fn books_share_the_request_budget() {
+        let (url, max_in_flight) = slow_server().await;
+        let endpoints = Endpoints::default()
+            .with_learning_url(url.clone())
+            .with_auth_url(url.clone());
+        let client = OreillyClient::new(3)
+            .with_endpoints(endpoints)
+            .cookies_auth(&[])
+            .await
+            .unwrap();
+
+        // Two books downloaded in parallel, each one with as many requests as allowed
+        let files = |book: &str| {
+            (0..6)
+                .map(|file| url.join(&format!("{}/{}", book, file)).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let (first, second) = (files("first"), files("second"));
+        let (first, second) = tokio::join!(
+            client.bulk_download_bytes(&first),
+            client.bulk_download_bytes(&second)
+        );
+        assert_eq!(first.unwrap().len() + second.unwrap().len(), 12);
+
+        let max_in_flight = max_in_flight.load(Ordering::SeqCst);
+        assert!((2..=3).contains(&max_in_flight), "{}", max_in_flight);
+    }
+
+    #[tokio::test]
+    async fn session_auth_without_session_or_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let asked = AtomicUsize::new(0);
+        let result = OreillyClient::default()
+            .session_auth(&dir.path().join("session.json"), None, || {
+                asked.fetch_add(1, Ordering::SeqCst);
+                Ok(None)
+            })
+            .await;
+        assert!(matches!(result, Err(OrlyError::NoCredentials)));
+        assert_eq!(asked.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn valid_session_does_not_ask_for_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        OreillyClient::default().save_session(&path).unwrap();
+        // The subscription is not checked when replaying, so any saved session is valid
+        OreillyClient::default()
+            .with_transport(Transport::replay(dir.path()).unwrap())
+            .session_auth(&path, None, || panic!("asked for credentials"))
+            .await
+            .unwrap();
+    }
+
+    thread_local! {
+        static LOGGED: std::cell::RefCell<Vec<String>> = Default::default();
+    }
+
+    /// Keeps the log messages of each thread, `tokio::test` runs the client on the test thread
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOGGED.with(|logged| logged.borrow_mut().push(record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Start capturing the log messages of the current test
+    fn capture_logs() {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CaptureLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        LOGGED.with(|logged| logged.borrow_mut().clear());
+    }
+
+    /// Assert that a message containing `text` was logged, without the secrets of
+    /// [`secret_server`]
+    fn assert_logged_redacted(text: &str) {
+        let logged = LOGGED.with(|logged| logged.borrow().clone());
+        let message = logged
+            .iter()
+            .find(|message| message.contains(text))
+            .unwrap_or_else(|| panic!("{:?} was not logged: {:#?}", text, logged));
+        assert!(!message.contains("SECRET"), "{}", message);
+        assert!(message.contains("***"), "{}", message);
+    }
+
+    /// Server with `SECRET` in the headers of every response and in the urls of every model.
+    /// `/flaky` fails every other request, `/expired` is always unauthorized and only `/etag`
+    /// can be revalidated.
+    async fn secret_server() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let flaky = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let base = url.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![0; 8192];
+                let len = stream.read(&mut request).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..len]).into_owned();
+                let target = request.split(' ').nth(1).unwrap_or_default();
+                let path = target.split('?').next().unwrap_or_default();
+
+                let chapter = format!("{}content/ch01.html?token=SECRET", base);
+                let (status, body) = match path {
+                    "/api/m/v2/auth/lookup/" => {
+                        ("200 OK", r#"{"password_login_allowed": true}"#.to_string())
+                    }
+                    "/api/v1/auth/login/" => ("200 OK", r#"{"logged_in": true}"#.to_string()),
+                    "/api/v1/" => (
+                        "200 OK",
+                        r#"{"subscription": {"cancellation_date": null}, "trial": {},
+                            "user": {"email": "SECRET@example.com"}}"#
+                            .to_string(),
+                    ),
+                    "/api/v1/book/1234/" => (
+                        "200 OK",
+                        serde_json::json!({
+                            "identifier": "1234",
+                            "isbn": "9781234567897",
+                            "cover": format!("{}cover.jpg?token=SECRET", base),
+                            "chapter_list": "",
+                            "toc": "",
+                            "flat_toc": "",
+                            "title": "Mock",
+                            "source": "",
+                            "pagecount": 1,
+                            "authors": [],
+                            "subjects": [],
+                            "publishers": [],
+                            "description": "",
+                            "issued": "2024-01-02",
+                            "language": "en",
+                        })
+                        .to_string(),
+                    ),
+                    "/api/v1/book/1234/chapter" => (
+                        "200 OK",
+                        serde_json::json!({
+                            "count": 1,
+                            "results": [{
+                                "asset_base_url": base,
+                                "title": "Chapter 1",
+                                "filename": "ch01.html",
+                                "images": [],
+                                "stylesheets": [],
+                                "site_styles": [],
+                                "content": chapter,
+                            }],
+                        })
+                        .to_string(),
+                    ),
+                    "/api/v1/book/1234/toc" => (
+                        "200 OK",
+                        serde_json::json!([{
+                            "depth": 1,
+                            "url": chapter,
+                            "minutes_required": 1.0,
+                            "fragment": "",
+                            "natural_key": [],
+                            "filename": "ch01.html",
+                            "label": "Chapter 1",
+                            "full_path": "ch01.html",
+                            "href": "ch01.html?token=SECRET",
+                            "id": "ch01",
+                            "media_type": "text/html",
+                            "children": [],
+                        }])
+                        .to_string(),
+                    ),
+                    "/content/ch01.html" => (
+                        "200 OK",
+                        r#"<p><a href="reset?token=SECRET">Reset</a></p>"#.to_string(),
+                    ),
+                    "/flaky" if !flaky.fetch_xor(true, Ordering::SeqCst) => {
+                        ("503 Service Unavailable", String::new())
+                    }
+                    "/expired" => ("401 Unauthorized", String::new()),
+                    "/api/v1/book/5678/toc" | "/api/v1/book/5678/chapter" => {
+                        ("403 Forbidden", String::new())
+                    }
+                    "/etag" if request.contains("if-none-match") => {
+                        ("304 Not Modified", String::new())
+                    }
+                    _ => ("200 OK", String::new()),
+                };
+                let etag = if path == "/etag" {
+                    "ETag: \"v1\"\r\n"
+                } else {
+                    ""
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nSet-Cookie: orm-jwt=SECRET; Path=/\r\n\
+                     X-Security-Token: SECRET\r\n{}Content-Length: {}\r\n\
+                     Connection: close\r\n\r\n{}",
+                    status,
+                    etag,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        url.parse().unwrap()
+    }
+
+    async fn secret_client(cache: Option<&Path>) -> OreillyClient<Authenticated> {
+        let url = secret_server().await;
+        let endpoints = Endpoints::default()
+            .with_learning_url(url.clone())
+            .with_auth_url(url);
+        let retry_policy = RetryPolicy {
+            retries: 1,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+        };
+        let mut client = OreillyClient::default()
+            .with_endpoints(endpoints)
+            .with_retry_policy(retry_policy);
+        if let Some(cache) = cache {
+            client = client.with_cache(Cache::new(cache).unwrap());
+        }
+        client
+            .cred_auth("jane@example.com", "hunter2")
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn book_failures_report_the_book_id() {
+        let client = secret_client(None).await;
+        for err in [
+            client.fetch_toc("5678").await.unwrap_err(),
+            client.fetch_book_chapters("5678").await.unwrap_err(),
+        ] {
+            assert!(
+                matches!(&err, OrlyError::Forbidden { book_id: Some(id), .. } if id == "5678"),
+                "{:?}",
+                err
+            );
+            assert!(err.to_string().contains("No access to book 5678"));
+        }
+    }
+
+    #[tokio::test]
+    async fn email_lookup_response_is_redacted() {
+        capture_logs();
+        secret_client(None).await;
+        assert_logged_redacted("Email lookup response");
+    }
+
+    #[tokio::test]
+    async fn auth_response_is_redacted() {
+        capture_logs();
+        secret_client(None).await;
+        assert_logged_redacted("Auth response");
+    }
+
+    #[tokio::test]
+    async fn billing_details_are_logged() {
+        capture_logs();
+        secret_client(None).await;
+        assert_logged_redacted("Billing details");
+    }
+
+    #[tokio::test]
+    async fn retried_url_is_redacted() {
+        let client = secret_client(None).await;
+        let url = client
+            .endpoints()
+            .learning_url
+            .join("flaky?token=SECRET")
+            .unwrap();
+        capture_logs();
+        client.download_text(url).await.unwrap();
+        assert_logged_redacted("succeeded after 2 attempts");
+    }
+
+    #[tokio::test]
+    async fn unauthorized_url_is_redacted() {
+        let client = secret_client(None).await;
+        let url = client
+            .endpoints()
+            .learning_url
+            .join("expired?token=SECRET")
+            .unwrap();
+        capture_logs();
+        assert!(client.download_text(url).await.is_err());
+        assert_logged_redacted("is unauthorized");
+    }
+
+    #[tokio::test]
+    async fn book_is_redacted() {
+        let client = secret_client(None).await;
+        capture_logs();
+        client.fetch_book_details("1234").await.unwrap();
+        assert_logged_redacted("Book: ");
+    }
+
+    #[tokio::test]
+    async fn cache_hit_url_is_redacted() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = secret_client(Some(dir.path())).await;
+        let url = client
+            .endpoints()
+            .learning_url
+            .join("file?token=SECRET")
+            .unwrap();
+        client.download(&url).await.unwrap();
+        capture_logs();
+        client.download(&url).await.unwrap();
+        assert_logged_redacted("Cache hit: ");
+    }
+
+    #[tokio::test]
+    async fn revalidated_url_is_redacted() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = secret_client(Some(dir.path())).await;
+        let url = client
+            .endpoints()
+            .learning_url
+            .join("etag?token=SECRET")
+            .unwrap();
+        client.download(&url).await.unwrap();
+        capture_logs();
+        client.download(&url).await.unwrap();
+        assert_logged_redacted("Cache entry is still fresh: ");
+    }
+
+    #[tokio::test]
+    async fn first_page_is_redacted() {
+        let client = secret_client(None).await;
+        capture_logs();
+        client.fetch_book_chapters_meta("1234").await.unwrap();
+        assert_logged_redacted("First page: ");
+    }
+
+    #[tokio::test]
+    async fn chapters_meta_is_redacted() {
+        let client = secret_client(None).await;
+        capture_logs();
+        client.fetch_book_chapters_meta("1234").await.unwrap();
+        assert_logged_redacted("Chapters meta: ");
+    }
+
+    #[tokio::test]
+    async fn chapter_content_is_redacted() {
+        let client = secret_client(None).await;
+        let meta = client.fetch_book_chapters_meta("1234").await.unwrap();
+        capture_logs();
+        client
+            .fetch_book_chapters_content("1234", meta)
+            .await
+            .unwrap();
+        assert_logged_redacted("Chapter content: ");
+    }
+
+    #[tokio::test]
+    async fn toc_is_redacted() {
+        let client = secret_client(None).await;
+        capture_logs();
+        client.fetch_toc("1234").await.unwrap();
+        assert_logged_redacted("Table of contants: ");
+    }
+}
@@ -0,0 +1,244 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use toml::{Table, Value};
+
+use crate::error::{OrlyError, Result};
+
+/// Defaults for the command line options, read from a toml file.
+///
+/// Keys are the long option names, e.g. `cookie`, `threads` or `cache-dir`. The `[profile.NAME]`
+/// tables hold named sets of options that override the top level ones. Any string value can
+/// be replaced by a reference, so that secrets don't need to be stored in the config:
+///
+/// ```toml
+/// cookie = { env = "ORLY_COOKIE" }
+/// threads = 10
+///
+/// [profile.kindle]
+/// kindle = true
+/// output = "/media/kindle/documents"
+/// creds = ["email@example.com", { file = "password.txt" }]
+/// ```
+#[derive(Debug)]
+pub struct Config {
+    path: PathBuf,
+    options: Table,
+    profiles: Table,
+}
+
+impl Config {
+    /// `orly/config.toml` in the user config directory
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("orly").join("config.toml"))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content =
+            std::fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+        let mut options = toml::from_str::<Table>(&content)
+            .with_context(|| format!("failed to parse config file {:?}", path))?;
+        let profiles = match options.remove("profile") {
+            Some(Value::Table(profiles)) => profiles,
+            Some(_) => {
+                return Err(OrlyError::InvalidConfig(
+                    "profile must be a table of profiles".to_string(),
+                ))
+            }
+            None => Table::new(),
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            options,
+            profiles,
+        })
+    }
+
+    /// Top level options merged with the options of `profile`
+    pub fn options(&self, profile: Option<&str>) -> Result<Table> {
+        let mut options = self.options.clone();
+        if let Some(name) = profile {
+            match self.profiles.get(name) {
+                Some(Value::Table(profile)) => options.extend(profile.clone()),
+                Some(_) => {
+                    return Err(OrlyError::InvalidConfig(format!(
+                        "profile {} must be a table",
+                        name
+                    )))
+                }
+                None => {
+                    return Err(OrlyError::InvalidConfig(format!(
+                        "profile {} not found in {:?}, available profiles: {}",
+                        name,
+                        self.path,
+                        self.profiles
+                            .keys()
+                            .map(String::as_str)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )))
+                }
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// Command line values of an option, with references resolved. Only options that are used
+    /// are resolved, so that a missing variable or file doesn't fail unrelated runs.
+    pub fn values(&self, key: &str, value: &Value) -> Result<Vec<String>> {
+        match value {
+            Value::Array(values) => values
+                .iter()
+                .map(|value| self.resolve(key, value))
+                .collect(),
+            value => Ok(vec![self.resolve(key, value)?]),
+        }
+    }
+
+    /// String form of a value, reading `{ env = "NAME" }` and `{ file = "PATH" }` references
+    fn resolve(&self, key: &str, value: &Value) -> Result<String> {
+        let invalid = || {
+            OrlyError::InvalidConfig(format!(
+                "{} must be a string, number, boolean, or an env or file reference",
+                key
+            ))
+        };
+
+        match value {
+            Value::String(value) => Ok(value.clone()),
+            Value::Integer(value) => Ok(value.to_string()),
+            Value::Float(value) => Ok(value.to_string()),
+            Value::Boolean(value) => Ok(value.to_string()),
+            Value::Table(reference) if reference.len() == 1 => {
+                match reference.iter().next().ok_or_else(invalid)? {
+                    (kind, Value::String(name)) if kind == "env" => {
+                        std::env::var(name).map_err(|_| {
+                            OrlyError::InvalidConfig(format!(
+                                "environment variable {} used by {} is not set",
+                                name, key
+                            ))
+                        })
+                    }
+                    (kind, Value::String(file)) if kind == "file" => {
+                        // Relative to the config file
+                        let file = self.path.parent().unwrap_or(Path::new(".")).join(file);
+                        let content = std::fs::read_to_string(&file).with_context(|| {
+                            format!("failed to read {:?} used by {}", file, key)
+                        })?;
+                        Ok(content.trim_end_matches(['\r', '\n']).to_string())
+                    }
+                    _ => Err(invalid()),
+                }
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(content: &str) -> (tempfile::TempDir, Config) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, content).unwrap();
+        let config = Config::from_file(&path).unwrap();
+        (dir, config)
+    }
+
+    fn values(config: &Config, options: &Table, key: &str) -> Vec<String> {
+        config.values(key, &options[key]).unwrap()
+    }
+
+    #[test]
+    fn from_file() {
+        let (_dir, config) = write_config(
+            r#"
+            threads = 10
+            kindle = true
+            creds = ["email@example.com", "hunter2"]
+            "#,
+        );
+        let options = config.options(None).unwrap();
+        assert_eq!(options.len(), 3);
+        assert_eq!(values(&config, &options, "threads"), ["10"]);
+        assert_eq!(values(&config, &options, "kindle"), ["true"]);
+        assert_eq!(
+            values(&config, &options, "creds"),
+            ["email@example.com", "hunter2"]
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        assert!(Config::from_file(&dir.path().join("missing.toml")).is_err());
+        let invalid = dir.path().join("invalid.toml");
+        std::fs::write(&invalid, "threads = ").unwrap();
+        assert!(Config::from_file(&invalid).is_err());
+        std::fs::write(&invalid, "profile = 1").unwrap();
+        assert!(matches!(
+            Config::from_file(&invalid),
+            Err(OrlyError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn profiles() {
+        let (_dir, config) = write_config(
+            r#"
+            threads = 10
+            output = "books"
+
+            [profile.kindle]
+            kindle = true
+            output = "/media/kindle"
+
+            [profile.broken]
+            "#,
+        );
+        // Profile options override the top level ones
+        let options = config.options(Some("kindle")).unwrap();
+        assert_eq!(values(&config, &options, "threads"), ["10"]);
+        assert_eq!(values(&config, &options, "output"), ["/media/kindle"]);
+        assert_eq!(values(&config, &options, "kindle"), ["true"]);
+        assert_eq!(config.options(Some("broken")).unwrap().len(), 2);
+
+        let err = config.options(Some("missing")).unwrap_err().to_string();
+        assert!(err.contains("profile missing not found"), "{}", err);
+        assert!(err.contains("broken, kindle"), "{}", err);
+
+        let (_dir, config) = write_config("[profile]\nkindle = 1\n");
+        assert!(matches!(
+            config.options(Some("kindle")),
+            Err(OrlyError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn references() {
+        std::env::set_var("ORLY_TEST_CONFIG_COOKIE", "session=1");
+        let (dir, config) = write_config(
+            r#"
+            cookie = { env = "ORLY_TEST_CONFIG_COOKIE" }
+            creds = ["email@example.com", { file = "password.txt" }]
+            email = { env = "ORLY_TEST_CONFIG_MISSING" }
+            password-file = { file = "missing.txt" }
+            output = { path = "books" }
+            threads = [[1]]
+            "#,
+        );
+        std::fs::write(dir.path().join("password.txt"), "hunter2\r\n").unwrap();
+        let options = config.options(None).unwrap();
+
+        assert_eq!(values(&config, &options, "cookie"), ["session=1"]);
+        // Files are relative to the config and the line ending is trimmed
+        assert_eq!(
+            values(&config, &options, "creds"),
+            ["email@example.com", "hunter2"]
+        );
+        for key in ["email", "password-file", "output", "threads"] {
+            assert!(config.values(key, &options[key]).is_err(), "{}", key);
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use log::{debug, info, warn};
+use rusqlite::{Connection, OpenFlags};
+
+use super::{import::ExportedCookie, ImportedCookie};
+use crate::error::{OrlyError, Result};
+
+/// Seconds between 1601-01-01, the Chromium timestamp epoch, and the unix epoch
+const CHROMIUM_EPOCH_OFFSET: i64 = 11_644_473_600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Browser {
+    Firefox,
+    Chrome,
+    Chromium,
+    Brave,
+    Edge,
+}
+
+impl fmt::Display for Browser {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Browser::Firefox => "firefox",
+            Browser::Chrome => "chrome",
+            Browser::Chromium => "chromium",
+            Browser::Brave => "brave",
+            Browser::Edge => "edge",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Browser and an optional profile name or path, parsed from `browser[:profile]`
+#[derive(Debug, Clone)]
+pub struct BrowserProfile {
+    pub browser: Browser,
+    pub profile: Option<String>,
+}
+
+impl FromStr for BrowserProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (browser, profile) = match s.split_once(':') {
+            Some((browser, profile)) => (browser, Some(profile.to_string())),
+            None => (s, None),
+        };
+
+        let browser = match browser.to_ascii_lowercase().as_str() {
+            "firefox" => Browser::Firefox,
+            "chrome" => Browser::Chrome,
+            "chromium" => Browser::Chromium,
+            "brave" => Browser::Brave,
+            "edge" => Browser::Edge,
+            other => {
+                return Err(format!(
+                    "Unsupported browser: {}. Supported browsers: firefox, chrome, chromium, brave, edge",
+                    other
+                ))
+            }
+        };
+
+        Ok(Self { browser, profile })
+    }
+}
+
+/// Read cookies of `cookie_domain` from a local browser profile.
+///
+/// Firefox stores cookies in plain text. Chromium based browsers encrypt cookie values on most
+/// systems, only the cookies stored unencrypted can be read.
+pub fn load_browser_cookies(
+    profile: &BrowserProfile,
+    cookie_domain: &str,
+) -> Result<Vec<ImportedCookie>> {
+    let cookies_db = match profile.browser {
+        Browser::Firefox => find_firefox_cookies(profile.profile.as_deref())?,
+        browser => find_chromium_cookies(browser, profile.profile.as_deref())?,
+    };
+    info!("Reading {} cookies from {:?}", profile.browser, cookies_db);
+    let exported = read_cookie_database(profile.browser, &cookies_db, cookie_domain)?;
+
+    let cookies = exported
+        .into_iter()
+        .filter_map(|cookie| cookie.into_imported(cookie_domain))
+        .collect::<Vec<_>>();
+    debug!("Found {} O'Reilly cookies", cookies.len());
+
+    if cookies.is_empty() {
+        warn!(
+            "No {} cookies found in {} profile, make sure you are signed in",
+            cookie_domain, profile.browser
+        );
+    }
+
+    Ok(cookies)
+}
+
+/// Read the cookies of `cookie_domain` from the cookie database of `browser`
+fn read_cookie_database(
+    browser: Browser,
+    cookies_db: &Path,
+    cookie_domain: &str,
+) -> Result<Vec<ExportedCookie>> {
+    // The browser keeps the database locked while running, work on a copy instead
+    let copy = tempfile::tempdir().context("failed to create temporary directory")?;
+    let copy_path = copy.path().join("cookies.sqlite");
+    fs::copy(cookies_db, &copy_path).with_context(|| format!("failed to copy {:?}", cookies_db))?;
+    // Recent changes might only be in the write-ahead log, which is only used together with
+    // its shared memory index
+    for suffix in ["-wal", "-shm"] {
+        let file = with_suffix(cookies_db, suffix);
+        if file.exists() {
+            fs::copy(&file, with_suffix(&copy_path, suffix))
+                .with_context(|| format!("failed to copy {:?}", file))?;
+        }
+    }
+
+    let connection = Connection::open_with_flags(&copy_path, OpenFlags::SQLITE_OPEN_READ_WRITE)
+        .with_context(|| format!("failed to open cookie database {:?}", cookies_db))?;
+
+    let exported = match browser {
+        Browser::Firefox => read_firefox_cookies(&connection, cookie_domain),
+        _ => read_chromium_cookies(&connection, cookie_domain),
+    }
+    .with_context(|| format!("failed to read cookie database {:?}", cookies_db))?;
+
+    Ok(exported)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn firefox_root() -> Option<PathBuf> {
+    if cfg!(target_os = "windows") {
+        dirs::config_dir().map(|dir| dir.join("Mozilla").join("Firefox").join("Profiles"))
+    } else if cfg!(target_os = "macos") {
+        dirs::config_dir().map(|dir| dir.join("Firefox").join("Profiles"))
+    } else {
+        dirs::home_dir().map(|dir| dir.join(".mozilla").join("firefox"))
+    }
+}
+
+/// Locate `cookies.sqlite`. Without an explicit profile the most recently used one is picked.
+fn find_firefox_cookies(profile: Option<&str>) -> Result<PathBuf> {
+    if let Some(path) = profile.map(Path::new).filter(|path| path.is_dir()) {
+        return Ok(path.join("cookies.sqlite"));
+    }
+
+    let root = firefox_root().context("unable to determine firefox profile directory")?;
+    let mut profiles = fs::read_dir(&root)
+        .with_context(|| format!("failed to list firefox profiles in {:?}", root))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.join("cookies.sqlite").is_file());
+
+    let found = match profile {
+        // Profile directories are named `<random>.<profile name>`
+        Some(name) => profiles.find(|path| {
+            path.file_name()
+                .and_then(|f| f.to_str())
+                .is_some_and(|f| f == name || f.ends_with(&format!(".{}", name)))
+        }),
+        None => profiles.max_by_key(|path| {
+            fs::metadata(path.join("cookies.sqlite"))
+                .and_then(|m| m.modified())
+                .ok()
+        }),
+    };
+
+    found
+        .map(|path| path.join("cookies.sqlite"))
+        .ok_or_else(|| {
+            OrlyError::AuthenticationFailed(format!(
+                "Firefox profile {} not found in {:?}",
+                profile.unwrap_or("with cookies"),
+                root
+            ))
+        })
+}
+
+fn chromium_root(browser: Browser) -> Option<PathBuf> {
+    let (windows, macos, linux) = match browser {
+        Browser::Chrome => ("Google/Chrome/User Data", "Google/Chrome", "google-chrome"),
+        Browser::Chromium => ("Chromium/User Data", "Chromium", "chromium"),
+        Browser::Brave => (
+            "BraveSoftware/Brave-Browser/User Data",
+            "BraveSoftware/Brave-Browser",
+            "BraveSoftware/Brave-Browser",
+        ),
+        Browser::Edge => (
+            "Microsoft/Edge/User Data",
+            "Microsoft Edge",
+            "microsoft-edge",
+        ),
+        Browser::Firefox => return None,
+    };
+
+    if cfg!(target_os = "windows") {
+        dirs::data_local_dir().map(|dir| dir.join(windows))
+    } else if cfg!(target_os = "macos") {
+        dirs::config_dir().map(|dir| dir.join(macos))
+    } else {
+        dirs::config_dir().map(|dir| dir.join(linux))
+    }
+}
+
+fn find_chromium_cookies(browser: Browser, profile: Option<&str>) -> Result<PathBuf> {
+    let profile_dir = match profile.map(PathBuf::from) {
+        Some(path) if path.is_dir() => path,
+        profile => chromium_root(browser)
+            .with_context(|| format!("unable to determine {} profile directory", browser))?
+            .join(profile.unwrap_or_else(|| PathBuf::from("Default"))),
+    };
+
+    // Newer versions keep cookies in the Network subdirectory
+    [
+        profile_dir.join("Network").join("Cookies"),
+        profile_dir.join("Cookies"),
+    ]
+    .into_iter()
+    .find(|path| path.is_file())
+    .ok_or_else(|| {
+        OrlyError::AuthenticationFailed(format!(
+            "{} cookie database not found in {:?}",
+            browser, profile_dir
+        ))
+    })
+}
+
+fn read_firefox_cookies(
+    connection: &Connection,
+    cookie_domain: &str,
+) -> rusqlite::Result<Vec<ExportedCookie>> {
+    let mut statement = connection.prepare(
+        "SELECT host, name, value, path, expiry, isSecure FROM moz_cookies \
+         WHERE host LIKE ?1",
+    )?;
+
+    let cookies = statement
+        .query_map([format!("%{}", cookie_domain)], |row| {
+            let expiry: i64 = row.get(4)?;
+            let host: String = row.get(0)?;
+            Ok(ExportedCookie {
+                // Domain cookies are stored with a leading dot
+                host_only: !host.starts_with('.'),
+                domain: host,
+                name: row.get(1)?,
+                value: row.get(2)?,
+                path: row.get(3)?,
+                // Newer versions store the expiration in milliseconds
+                expires: Some(if expiry > 100_000_000_000 {
+                    expiry / 1000
+                } else {
+                    expiry
+                }),
+                secure: row.get::<_, i64>(5)? != 0,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok(cookies)
+}
+
+fn read_chromium_cookies(
+    connection: &Connection,
+    cookie_domain: &str,
+) -> rusqlite::Result<Vec<ExportedCookie>> {
+    let mut statement = connection.prepare(
+        "SELECT host_key, name, value, length(encrypted_value), path, expires_utc, is_secure \
+         FROM cookies WHERE host_key LIKE ?1",
+    )?;
+
+    let mut encrypted = 0;
+    let mut cookies = Vec::new();
+    let rows = statement.query_map([format!("%{}", cookie_domain)], |row| {
+        let host: String = row.get(0)?;
+        Ok((
+            ExportedCookie {
+                host_only: !host.starts_with('.'),
+                domain: host,
+                name: row.get(1)?,
+                value: row.get(2)?,
+                path: row.get(4)?,
+                expires: match row.get::<_, i64>(5)? {
+                    0 => None,
+                    micros => Some(micros / 1_000_000 - CHROMIUM_EPOCH_OFFSET),
+                },
+                secure: row.get::<_, i64>(6)? != 0,
+            },
+            row.get::<_, Option<i64>>(3)?.unwrap_or(0),
+        ))
+    })?;
+
+    for row in rows {
+        let (cookie, encrypted_len) = row?;
+        if cookie.value.is_empty() && encrypted_len > 0 {
+            encrypted += 1;
+            continue;
+        }
+        cookies.push(cookie);
+    }
+
+    if encrypted > 0 {
+        warn!(
+            "Skipped {} encrypted cookies, export them with a cookie export extension and use --cookie-file instead",
+            encrypted
+        );
+    }
+
+    Ok(cookies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIREFOX: &str = include_str!("../../tests/fixtures/cookies/firefox.sql");
+    const CHROMIUM: &str = include_str!("../../tests/fixtures/cookies/chromium.sql");
+
+    fn database(dir: &Path, fixture: &str) -> (PathBuf, Connection) {
+        let path = dir.join("cookies.sqlite");
+        let connection = Connection::open(&path).unwrap();
+        connection.execute_batch(fixture).unwrap();
+        (path, connection)
+    }
+
+    fn names(cookies: &[ExportedCookie]) -> Vec<&str> {
+        let mut names = cookies
+            .iter()
+            .map(|cookie| cookie.name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn firefox() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = database(dir.path(), FIREFOX);
+
+        let cookies = read_cookie_database(Browser::Firefox, &path, "oreilly.com").unwrap();
+        assert_eq!(
+            names(&cookies),
+            ["expired", "orm-jwt", "orm-rt", "sessionid"]
+        );
+
+        let refresh = cookies.iter().find(|c| c.name == "orm-rt").unwrap();
+        assert_eq!(refresh.expires, Some(4102444800));
+        let session = cookies.iter().find(|c| c.name == "sessionid").unwrap();
+        assert!(session.host_only && session.secure);
+        let jwt = cookies.iter().find(|c| c.name == "orm-jwt").unwrap();
+        assert!(!jwt.host_only);
+    }
+
+    #[test]
+    fn chromium() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = database(dir.path(), CHROMIUM);
+
+        let cookies = read_cookie_database(Browser::Chromium, &path, "oreilly.com").unwrap();
+        assert_eq!(names(&cookies), ["orm-jwt", "sessionid"]);
+
+        let jwt = cookies.iter().find(|c| c.name == "orm-jwt").unwrap();
+        assert_eq!(jwt.expires, Some(4102444800));
+        assert!(!jwt.host_only);
+        let session = cookies.iter().find(|c| c.name == "sessionid").unwrap();
+        assert_eq!(session.expires, None);
+        assert!(session.host_only);
+    }
+
+    #[test]
+    fn changes_in_the_write_ahead_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, connection) = database(dir.path(), FIREFOX);
+        // Like a running browser: the connection stays open and the new cookie is only in the
+        // write-ahead log
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .unwrap();
+        connection
+            .pragma_update(None, "wal_autocheckpoint", 0)
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO moz_cookies (name, value, host, path, expiry, isSecure) \
+                 VALUES ('fresh', 'new', '.oreilly.com', '/', 4102444800, 1)",
+                [],
+            )
+            .unwrap();
+        assert!(with_suffix(&path, "-wal").exists());
+        assert!(with_suffix(&path, "-shm").exists());
+
+        let cookies = read_cookie_database(Browser::Firefox, &path, "oreilly.com").unwrap();
+        assert!(names(&cookies).contains(&"fresh"));
+    }
+
+    #[test]
+    fn browser_profile() {
+        let profile = "firefox:work".parse::<BrowserProfile>().unwrap();
+        assert_eq!(profile.browser, Browser::Firefox);
+        assert_eq!(profile.profile.as_deref(), Some("work"));
+        assert_eq!(
+            "Chrome".parse::<BrowserProfile>().unwrap().browser,
+            Browser::Chrome
+        );
+        assert!("safari".parse::<BrowserProfile>().is_err());
+    }
+}
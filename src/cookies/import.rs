@@ -0,0 +1,289 @@
+use std::path::Path;
+
+use anyhow::Context;
+use log::{debug, warn};
+use reqwest_cookie_store::RawCookie;
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+use super::ImportedCookie;
+use crate::error::{OrlyError, Result};
+
+/// Cookie as exported by a browser, before it is added to the cookie store
+#[derive(Debug)]
+pub(crate) struct ExportedCookie {
+    pub domain: String,
+    /// Whether the cookie is only sent to `domain` itself, not to its subdomains
+    pub host_only: bool,
+    pub path: String,
+    pub secure: bool,
+    /// Unix timestamp, `None` for session cookies
+    pub expires: Option<i64>,
+    pub name: String,
+    pub value: String,
+}
+
+impl ExportedCookie {
+    /// Convert to a cookie that can be added to the store. Cookies outside of `cookie_domain`
+    /// and expired cookies are skipped.
+    pub fn into_imported(self, cookie_domain: &str) -> Option<ImportedCookie> {
+        let domain = self.domain.trim_start_matches('.');
+        if !super::matches_domain(domain, cookie_domain) {
+            return None;
+        }
+
+        let expires = match self.expires.map(OffsetDateTime::from_unix_timestamp) {
+            Some(Ok(expires)) if expires < OffsetDateTime::now_utc() => {
+                debug!("Skipping expired cookie {}", self.name);
+                return None;
+            }
+            Some(Ok(expires)) => Some(expires),
+            Some(Err(_)) => return None,
+            None => None,
+        };
+
+        let mut builder = RawCookie::build((self.name, self.value))
+            .path(self.path)
+            .secure(self.secure);
+        // Without a domain the cookie store keeps the cookie to the host it was set by
+        if !self.host_only {
+            builder = builder.domain(domain.to_string());
+        }
+        if let Some(expires) = expires {
+            builder = builder.expires(expires);
+        }
+
+        Some(ImportedCookie {
+            cookie: builder.build(),
+            host: domain.to_string(),
+        })
+    }
+}
+
+/// Entry of the json array produced by cookie export extensions like Cookie-Editor or
+/// EditThisCookie
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct JsonCookie {
+    domain: String,
+    name: String,
+    value: String,
+    #[serde(default = "default_path")]
+    path: String,
+    #[serde(default)]
+    secure: bool,
+    #[serde(default)]
+    session: bool,
+    #[serde(default)]
+    host_only: bool,
+    #[serde(alias = "expires", alias = "expiry")]
+    expiration_date: Option<f64>,
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+/// Parse cookies in the Netscape `cookies.txt` format: one cookie per line with tab separated
+/// domain, include subdomains flag, path, secure flag, expiration, name and value.
+pub(crate) fn parse_netscape(content: &str) -> Result<Vec<ExportedCookie>> {
+    let mut cookies = Vec::new();
+
+    for (number, line) in content.lines().enumerate() {
+        // curl marks http only cookies with a prefix instead of a separate column
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields = line.split('\t').collect::<Vec<&str>>();
+        if fields.len() != 7 {
+            return Err(OrlyError::ParseError(format!(
+                "Invalid cookies.txt line {}: expected 7 tab separated fields, found {}",
+                number + 1,
+                fields.len()
+            )));
+        }
+
+        let expires = fields[4].trim().parse::<i64>().map_err(|_| {
+            OrlyError::ParseError(format!(
+                "Invalid cookie expiration on line {}: {}",
+                number + 1,
+                fields[4]
+            ))
+        })?;
+
+        cookies.push(ExportedCookie {
+            domain: fields[0].to_string(),
+            host_only: !fields[1].eq_ignore_ascii_case("TRUE"),
+            path: fields[2].to_string(),
+            secure: fields[3].eq_ignore_ascii_case("TRUE"),
+            expires: (expires != 0).then_some(expires),
+            name: fields[5].to_string(),
+            value: fields[6].to_string(),
+        });
+    }
+
+    Ok(cookies)
+}
+
+/// Parse the json array format used by common cookie export browser extensions
+pub(crate) fn parse_json(content: &str) -> Result<Vec<ExportedCookie>> {
+    let cookies = serde_json::from_str::<Vec<JsonCookie>>(content)?;
+
+    Ok(cookies
+        .into_iter()
+        .map(|cookie| ExportedCookie {
+            domain: cookie.domain,
+            host_only: cookie.host_only,
+            path: cookie.path,
+            secure: cookie.secure,
+            expires: if cookie.session {
+                None
+            } else {
+                cookie.expiration_date.map(|expires| expires as i64)
+            },
+            name: cookie.name,
+            value: cookie.value,
+        })
+        .collect())
+}
+
+/// Load cookies of `cookie_domain` from a Netscape `cookies.txt` or a json cookie export. The
+/// format is detected from the file content.
+pub fn load_cookie_file(path: &Path, cookie_domain: &str) -> Result<Vec<ImportedCookie>> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+
+    let exported = if content.trim_start().starts_with('[') {
+        parse_json(&content)?
+    } else {
+        parse_netscape(&content)?
+    };
+
+    let total = exported.len();
+    let cookies = exported
+        .into_iter()
+        .filter_map(|cookie| cookie.into_imported(cookie_domain))
+        .collect::<Vec<_>>();
+
+    debug!(
+        "Imported {} of {} cookies from {:?}",
+        cookies.len(),
+        total,
+        path
+    );
+    if cookies.is_empty() {
+        warn!("No valid O'Reilly cookies found in {:?}", path);
+    }
+
+    Ok(cookies)
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use reqwest_cookie_store::CookieStore;
+
+    use super::*;
+    use crate::cookies::insert_cookies;
+
+    const NETSCAPE: &str = "# Netscape HTTP Cookie File\n\
+        # This is a comment\n\
+        \n\
+        .oreilly.com\tTRUE\t/\tTRUE\t0\torm-jwt\tjwt\n\
+        #HttpOnly_learning.oreilly.com\tFALSE\t/\tTRUE\t4102444800\tsessionid\tsession\n\
+        .example.com\tTRUE\t/\tFALSE\t0\tother\tvalue\n";
+
+    fn names(store: &CookieStore, url: &str) -> Vec<String> {
+        let mut names = store
+            .matches(&Url::parse(url).unwrap())
+            .into_iter()
+            .map(|cookie| cookie.name().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn netscape() {
+        let cookies = parse_netscape(NETSCAPE).unwrap();
+        assert_eq!(cookies.len(), 3);
+
+        assert_eq!(cookies[0].domain, ".oreilly.com");
+        assert!(!cookies[0].host_only);
+        assert_eq!(cookies[0].expires, None);
+        assert!(cookies[0].secure);
+
+        // curl marks http only cookies with a prefix, they are not comments
+        assert_eq!(cookies[1].name, "sessionid");
+        assert_eq!(cookies[1].domain, "learning.oreilly.com");
+        assert!(cookies[1].host_only);
+        assert_eq!(cookies[1].expires, Some(4102444800));
+    }
+
+    #[test]
+    fn netscape_malformed() {
+        let err = parse_netscape("# comment\n.oreilly.com\tTRUE\t/\tTRUE\t0\tname\n").unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+
+        let err = parse_netscape(".oreilly.com\tTRUE\t/\tTRUE\tnever\tname\tvalue\n").unwrap_err();
+        assert!(err.to_string().contains("expiration on line 1"));
+    }
+
+    #[test]
+    fn json() {
+        let cookies = parse_json(
+            r#"[
+                {"domain": ".oreilly.com", "name": "orm-jwt", "value": "jwt", "session": true,
+                 "expirationDate": 4102444800.5},
+                {"domain": "learning.oreilly.com", "hostOnly": true, "name": "sessionid",
+                 "value": "session", "path": "/api", "secure": true, "expirationDate": 4102444800.5}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(cookies.len(), 2);
+        assert_eq!(cookies[0].expires, None);
+        assert_eq!(cookies[0].path, "/");
+        assert!(!cookies[0].host_only);
+        assert_eq!(cookies[1].expires, Some(4102444800));
+        assert_eq!(cookies[1].path, "/api");
+        assert!(cookies[1].host_only && cookies[1].secure);
+
+        assert!(parse_json(r#"[{"name": "no domain", "value": ""}]"#).is_err());
+        assert!(parse_json("[{").is_err());
+    }
+
+    #[test]
+    fn host_only_cookies_are_not_sent_to_subdomains() {
+        let cookies = parse_netscape(NETSCAPE)
+            .unwrap()
+            .into_iter()
+            .filter_map(|cookie| cookie.into_imported("oreilly.com"))
+            .collect::<Vec<_>>();
+        // Cookies of other domains are skipped
+        assert_eq!(cookies.len(), 2);
+
+        let mut store = CookieStore::default();
+        insert_cookies(&mut store, &cookies).unwrap();
+
+        assert_eq!(
+            names(&store, "https://learning.oreilly.com/"),
+            ["orm-jwt", "sessionid"]
+        );
+        assert_eq!(
+            names(&store, "https://api.learning.oreilly.com/"),
+            ["orm-jwt"]
+        );
+        assert_eq!(names(&store, "https://www.oreilly.com/"), ["orm-jwt"]);
+    }
+
+    #[test]
+    fn expired_cookies_are_skipped() {
+        let cookies = parse_netscape(".oreilly.com\tTRUE\t/\tTRUE\t1000\tname\tvalue\n").unwrap();
+        assert!(cookies
+            .into_iter()
+            .all(|cookie| cookie.into_imported("oreilly.com").is_none()));
+    }
+}
@@ -0,0 +1,168 @@
+mod browser;
+mod import;
+
+pub use browser::{load_browser_cookies, Browser, BrowserProfile};
+pub use import::load_cookie_file;
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use reqwest::Url;
+use reqwest_cookie_store::{CookieStore, RawCookie};
+
+use crate::error::{OrlyError, Result};
+
+/// Whether `domain` is `cookie_domain` or one of its subdomains
+pub(crate) fn matches_domain(domain: &str, cookie_domain: &str) -> bool {
+    let domain = domain.trim_start_matches('.');
+    domain == cookie_domain || domain.ends_with(&format!(".{}", cookie_domain))
+}
+
+/// Cookie from a header, a cookie file or a browser, ready to be added to the session
+#[derive(Debug, Clone)]
+pub struct ImportedCookie {
+    pub cookie: RawCookie<'static>,
+    /// Host the cookie was set by. A cookie without a domain is only sent to this exact host,
+    /// not to its subdomains.
+    pub host: String,
+}
+
+/// Platform specific location of the saved session, e.g. `~/.local/share/orly/session.json`
+/// on linux
+pub fn default_session_path() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|dir| dir.join("orly").join("session.json"))
+}
+
+/// Parse a `Cookie` header value (`name1=value1; name2=value2`). Cookies are scoped to
+/// `cookie_domain`.
+pub fn parse_cookie_header(header: &str, cookie_domain: &str) -> Result<Vec<ImportedCookie>> {
+    header
+        .split(';')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .enumerate()
+        .map(|(position, pair)| {
+            // The pair itself is a secret and must not end up in the logs
+            let (name, value) = pair.split_once('=').ok_or_else(|| {
+                OrlyError::ParseError(format!(
+                    "Invalid cookie {}, expected name=value",
+                    position + 1
+                ))
+            })?;
+            Ok(ImportedCookie {
+                cookie: RawCookie::build((name.trim().to_string(), value.trim().to_string()))
+                    .domain(cookie_domain.to_string())
+                    .path("/")
+                    .build(),
+                host: cookie_domain.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Add cookies to the store as if they were set by their host
+pub(crate) fn insert_cookies(store: &mut CookieStore, cookies: &[ImportedCookie]) -> Result<()> {
+    for ImportedCookie { cookie, host } in cookies {
+        let url = Url::parse(&format!("https://{}/", host))
+            .with_context(|| format!("invalid cookie domain: {}", host))?;
+        store
+            .insert_raw(cookie, &url)
+            .map_err(|err| anyhow::anyhow!("Invalid cookie {}: {}", cookie.name(), err))?;
+    }
+    Ok(())
+}
+
+/// Load a cookie store saved with [`save_session`]. Expired cookies are skipped.
+pub(crate) fn load_session(path: &Path) -> Result<CookieStore> {
+    let file = File::open(path).with_context(|| format!("failed to open {:?}", path))?;
+    let store = CookieStore::load_json(BufReader::new(file))
+        .map_err(|err| anyhow::anyhow!("failed to parse session file {:?}: {}", path, err))?;
+    Ok(store)
+}
+
+/// Save all cookies, including session cookies, so that the session can be restored on the
+/// next run. The file is only readable by the current user.
+pub(crate) fn save_session(store: &CookieStore, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("failed to create {:?}", parent))?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let file = options
+        .open(path)
+        .with_context(|| format!("failed to create {:?}", path))?;
+    // The mode only applies to new files, a session saved by an older version may be readable
+    // by others
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("failed to set permissions of {:?}", path))?;
+    }
+    store
+        .save_incl_expired_and_nonpersistent_json(&mut BufWriter::new(file))
+        .map_err(|err| anyhow::anyhow!("failed to save session to {:?}: {}", path, err))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookie_header() {
+        let cookies = parse_cookie_header("a=1; b = 2 ;", "oreilly.com").unwrap();
+        assert_eq!(cookies.len(), 2);
+        let cookie = &cookies[1].cookie;
+        assert_eq!((cookie.name(), cookie.value()), ("b", "2"));
+        assert_eq!(cookie.domain(), Some("oreilly.com"));
+
+        let err = parse_cookie_header("a=1; secret", "oreilly.com").unwrap_err();
+        assert!(!err.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn session_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("orly").join("session.json");
+        let mut store = CookieStore::default();
+        insert_cookies(
+            &mut store,
+            &parse_cookie_header("orm-jwt=token", "oreilly.com").unwrap(),
+        )
+        .unwrap();
+
+        save_session(&store, &path).unwrap();
+        let restored = load_session(&path).unwrap();
+        assert_eq!(
+            restored.get("oreilly.com", "/", "orm-jwt").unwrap().value(),
+            "token"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn session_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        fs::write(&path, "{}").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        save_session(&CookieStore::default(), &path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
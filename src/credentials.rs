@@ -0,0 +1,186 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use log::debug;
+
+use crate::error::{OrlyError, Result};
+
+/// Login and password of a `machine` entry of a netrc file
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NetrcEntry {
+    pub login: Option<String>,
+    pub password: Option<String>,
+}
+
+/// `$NETRC`, or `.netrc` (`_netrc` on Windows) in the home directory
+pub fn default_netrc_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("NETRC") {
+        return Some(PathBuf::from(path));
+    }
+    let name = if cfg!(windows) { "_netrc" } else { ".netrc" };
+    dirs::home_dir().map(|dir| dir.join(name))
+}
+
+/// Find the entry of `host` in a netrc file. Returns `None` if the file does not exist. The
+/// `default` entry is ignored, it is meant for other hosts than O'Reilly.
+pub fn netrc_entry(path: &Path, host: &str) -> Result<Option<NetrcEntry>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+
+    // Entry the following login and password belong to: the host, or `None` for other entries
+    let mut current = None;
+    let mut host_entry = None;
+    let mut tokens = Tokens::new(&content);
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" => {
+                current = match tokens.next() {
+                    Some(machine) if machine == host && host_entry.is_none() => {
+                        Some(host_entry.insert(NetrcEntry::default()))
+                    }
+                    _ => None,
+                };
+            }
+            "default" => current = None,
+            "login" | "password" | "account" => {
+                let value = tokens.next().map(str::to_string);
+                if let Some(entry) = &mut current {
+                    match token {
+                        "login" => entry.login = value,
+                        "password" => entry.password = value,
+                        _ => {}
+                    }
+                }
+            }
+            "macdef" => {
+                current = None;
+                tokens.skip_macro();
+            }
+            token => {
+                return Err(OrlyError::ParseError(format!(
+                    "unexpected token {:?} in {:?}",
+                    token, path
+                )))
+            }
+        }
+    }
+
+    if host_entry.is_some() {
+        debug!("Found credentials for {} in {:?}", host, path);
+    }
+    Ok(host_entry)
+}
+
+/// Whitespace separated netrc tokens
+struct Tokens<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(content: &'a str) -> Self {
+        Self { rest: content }
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() {
+            self.rest = rest;
+            return None;
+        }
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        self.rest = &rest[end..];
+        Some(&rest[..end])
+    }
+
+    /// Macro definitions end with an empty line
+    fn skip_macro(&mut self) {
+        self.rest = match self.rest.find("\n\n") {
+            Some(end) => &self.rest[end..],
+            None => "",
+        };
+    }
+}
+
+/// Read a password from the first line of a file
+pub fn read_password_file(path: &Path) -> Result<String> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+    let password = content.lines().next().unwrap_or_default().to_string();
+    if password.is_empty() {
+        return Err(OrlyError::AuthenticationFailed(format!(
+            "password file {:?} is empty",
+            path
+        )));
+    }
+    Ok(password)
+}
+
+/// Ask for the password of `email` on the terminal without echoing it
+pub fn prompt_password(email: &str) -> Result<String> {
+    let password = rpassword::prompt_password(format!("Password for {}: ", email))
+        .context("failed to read the password")?;
+    Ok(password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(content: &str, host: &str) -> Option<NetrcEntry> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("netrc");
+        std::fs::write(&path, content).unwrap();
+        netrc_entry(&path, host).unwrap()
+    }
+
+    #[test]
+    fn machine_entry() {
+        let netrc = "machine example.com login other password secret\n\
+                     machine api.oreilly.com\n  login me@example.com\n  password hunter2\n";
+        assert_eq!(
+            entry(netrc, "api.oreilly.com"),
+            Some(NetrcEntry {
+                login: Some("me@example.com".to_string()),
+                password: Some("hunter2".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn default_entry_is_ignored() {
+        let netrc = "machine example.com login other password secret\n\
+                     default login anonymous password guest\n";
+        assert_eq!(entry(netrc, "api.oreilly.com"), None);
+    }
+
+    #[test]
+    fn macros_are_skipped() {
+        let netrc = "macdef init\ncd /pub\nmachine api.oreilly.com\n\n\
+                     machine api.oreilly.com login me@example.com\n";
+        let entry = entry(netrc, "api.oreilly.com").unwrap();
+        assert_eq!(entry.login.as_deref(), Some("me@example.com"));
+        assert_eq!(entry.password, None);
+    }
+
+    #[test]
+    fn missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            netrc_entry(&dir.path().join("netrc"), "host").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn password_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("password");
+        std::fs::write(&path, "hunter2\nignored\n").unwrap();
+        assert_eq!(read_password_file(&path).unwrap(), "hunter2");
+        std::fs::write(&path, "\n").unwrap();
+        assert!(read_password_file(&path).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use std::path::Path;
+
+use anyhow::Context;
+use reqwest::Url;
+use serde::Deserialize;
+use url::Host;
+
+use crate::error::Result;
+
+/// Every host and url used to talk to O'Reilly.
+///
+/// Defaults to the public O'Reilly hosts. Can be pointed to a mock server for testing or to an
+/// enterprise or regional deployment.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Endpoints {
+    /// Base url of the learning platform: book details, chapters, table of contents and files
+    pub learning_url: Url,
+    /// Base url of the authentication api
+    pub auth_url: Url,
+    /// Domain the session cookies are scoped to, cookies of other domains are not imported.
+    /// Derived from `learning_url` if not set, see [`Endpoints::cookie_domain`].
+    #[serde(rename = "cookie_domain")]
+    pub cookie_domain_override: Option<String>,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self {
+            learning_url: "https://learning.oreilly.com/"
+                .parse()
+                .expect("correct learning url"),
+            auth_url: "https://api.oreilly.com/"
+                .parse()
+                .expect("correct auth url"),
+            cookie_domain_override: None,
+        }
+    }
+}
+
+impl Endpoints {
+    /// Load endpoints from a toml file. Missing keys fall back to the defaults.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content =
+            std::fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+        let endpoints = toml::from_str::<Self>(&content)
+            .with_context(|| format!("failed to parse endpoints file {:?}", path))?;
+        Ok(endpoints)
+    }
+
+    pub fn with_learning_url(self, learning_url: Url) -> Self {
+        Self {
+            learning_url,
+            ..self
+        }
+    }
+
+    pub fn with_auth_url(self, auth_url: Url) -> Self {
+        Self { auth_url, ..self }
+    }
+
+    pub fn with_cookie_domain(self, cookie_domain: String) -> Self {
+        Self {
+            cookie_domain_override: Some(cookie_domain),
+            ..self
+        }
+    }
+
+    /// Domain the session cookies are scoped to: the parent domain of the learning host, e.g.
+    /// `oreilly.com` for `learning.oreilly.com`, or the host itself if it has no subdomain or
+    /// is an ip address
+    pub fn cookie_domain(&self) -> String {
+        if let Some(cookie_domain) = &self.cookie_domain_override {
+            return cookie_domain.clone();
+        }
+        match self.learning_url.host() {
+            Some(Host::Domain(host)) => match host.split_once('.') {
+                Some((_, parent)) if parent.contains('.') => parent.to_string(),
+                _ => host.to_string(),
+            },
+            Some(host) => host.to_string(),
+            None => String::new(),
+        }
+    }
+
+    fn join(base: &Url, endpoint: &str) -> Result<Url> {
+        // Without the trailing slash the last path segment of the base url would be replaced
+        let mut base = base.clone();
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        Ok(base
+            .join(endpoint)
+            .with_context(|| format!("invalid endpoint: {}", endpoint))?)
+    }
+
+    pub fn billing(&self) -> Result<Url> {
+        Self::join(&self.learning_url, "api/v1/")
+    }
+
+    pub fn book(&self, book_id: &str) -> Result<Url> {
+        Self::join(&self.learning_url, &format!("api/v1/book/{}/", book_id))
+    }
+
+    pub fn chapters(&self, book_id: &str) -> Result<Url> {
+        Self::join(
+            &self.learning_url,
+            &format!("api/v1/book/{}/chapter", book_id),
+        )
+    }
+
+    pub fn toc(&self, book_id: &str) -> Result<Url> {
+        Self::join(&self.learning_url, &format!("api/v1/book/{}/toc", book_id))
+    }
+
+    /// Base url of the images, stylesheets and other files of a book
+    pub fn book_files(&self, identifier: &str) -> Result<Url> {
+        Self::join(
+            &self.learning_url,
+            &format!("api/v2/epubs/urn:orm:book:{}/files/", identifier),
+        )
+    }
+
+    pub fn login_lookup(&self) -> Result<Url> {
+        Self::join(&self.auth_url, "api/m/v2/auth/lookup/")
+    }
+
+    pub fn login(&self) -> Result<Url> {
+        Self::join(&self.auth_url, "api/v1/auth/login/")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn learning(url: &str) -> Endpoints {
+        Endpoints::default().with_learning_url(url.parse().unwrap())
+    }
+
+    #[test]
+    fn cookie_domain_of_the_learning_host() {
+        assert_eq!(Endpoints::default().cookie_domain(), "oreilly.com");
+        assert_eq!(
+            learning("https://learning.acme.example.com/").cookie_domain(),
+            "acme.example.com"
+        );
+        assert_eq!(
+            learning("http://localhost:8080/").cookie_domain(),
+            "localhost"
+        );
+        assert_eq!(
+            learning("http://example.com/").cookie_domain(),
+            "example.com"
+        );
+        assert_eq!(
+            learning("http://127.0.0.1:8770/").cookie_domain(),
+            "127.0.0.1"
+        );
+    }
+
+    #[test]
+    fn explicit_cookie_domain() {
+        let endpoints = learning("http://127.0.0.1:8770/").with_cookie_domain("test".to_string());
+        assert_eq!(endpoints.cookie_domain(), "test");
+
+        let endpoints = toml::from_str::<Endpoints>(
+            "learning_url = \"https://learning.acme.com/\"\ncookie_domain = \"acme.com\"",
+        )
+        .unwrap();
+        assert_eq!(endpoints.cookie_domain(), "acme.com");
+        assert_eq!(endpoints.auth_url, Endpoints::default().auth_url);
+    }
+
+    #[test]
+    fn endpoints_keep_the_base_path() {
+        let endpoints = learning("https://proxy.example.com/oreilly");
+        assert_eq!(
+            endpoints.book("1234").unwrap().as_str(),
+            "https://proxy.example.com/oreilly/api/v1/book/1234/"
+        );
+    }
+}
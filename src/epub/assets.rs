@@ -0,0 +1,76 @@
+use std::{collections::HashMap, path::Path};
+
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+
+/// Number of hex digits of the content hash in the file names
+const HASH_LEN: usize = 10;
+
+/// File names of the images and stylesheet dependencies of a book.
+///
+/// Names are derived from the content, so files with the same name in different directories
+/// never overwrite each other, and identical files are only stored once.
+#[derive(Debug, Default)]
+pub(crate) struct Assets {
+    by_url: HashMap<Url, String>,
+    by_hash: HashMap<String, String>,
+}
+
+impl Assets {
+    /// Name of an already named file with the same content, from now on also used for `url`
+    pub fn duplicate(&mut self, url: &Url, content: &[u8]) -> Option<String> {
+        let name = self.by_hash.get(&hash(content))?.clone();
+        self.by_url.insert(url.clone(), name.clone());
+        Some(name)
+    }
+
+    /// Name `url` in `dir` as `<stem>-<hash>.<extension>`. The extension of the url is used
+    /// unless `extension` is given, e.g. because the file was converted to another format.
+    pub fn insert(
+        &mut self,
+        dir: &str,
+        url: &Url,
+        content: &[u8],
+        extension: Option<&str>,
+    ) -> String {
+        let hash = hash(content);
+        let name = asset_name(dir, url, &hash[..HASH_LEN], extension);
+        self.by_hash.insert(hash, name.clone());
+        self.by_url.insert(url.clone(), name.clone());
+        name
+    }
+
+    pub fn get(&self, url: &Url) -> Option<&String> {
+        self.by_url.get(url)
+    }
+}
+
+fn hash(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Characters other than ascii letters, digits, `-` and `_` are replaced, so that the manifest
+/// ids derived from the name are valid
+fn asset_name(dir: &str, url: &Url, hash: &str, extension: Option<&str>) -> String {
+    let path = Path::new(url.path());
+    let clean = |s: &str| {
+        s.chars()
+            .map(|c| match c {
+                c if c.is_ascii_alphanumeric() || c == '-' || c == '_' => c,
+                _ => '_',
+            })
+            .collect::<String>()
+    };
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(clean)
+        .unwrap_or_default();
+    match extension.or_else(|| path.extension().and_then(|ext| ext.to_str())) {
+        Some(extension) => format!("{}/{}-{}.{}", dir, stem, hash, clean(extension)),
+        None => format!("{}/{}-{}", dir, stem, hash),
+    }
+}
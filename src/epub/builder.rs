@@ -1,16 +1,26 @@
-use std::collections::{HashMap, HashSet};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use indexmap::{IndexMap, IndexSet};
 
 use crate::{
     client::{Authenticated, OreillyClient},
-    epub::lxml::DocumentExt,
+    endpoints::Endpoints,
+    epub::{
+        assets::Assets,
+        fingerprint::fingerprint,
+        images::{self, ImagePolicies, ImagePolicy},
+        lxml::DocumentExt,
+    },
     error::{OrlyError, Result},
     models::{Book, Chapter, TocElement},
-    templates::{ChapterXhtml, ContainerXml, ContentOpf, IbooksXml, NavPoint, Toc},
+    progress::{Phase, ProgressCallback, Tracker},
+    templates::{ChapterXhtml, ContainerXml, ContentOpf, IbooksXml, Nav, NavItem, NavPoint, Toc},
 };
 use std::{
+    collections::HashSet,
     ffi::OsStr,
     io::Cursor,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
 use anyhow::Context;
@@ -44,40 +54,89 @@ const XHTML: &str = "xhtml";
 const IMAGES: &str = "Images";
 const STYLES: &str = "Styles";
 const TEXT: &str = "Text";
+/// Stands in for the images and stylesheet dependencies missing on the server. Asset names
+/// always end with a hash, so it never clashes with a downloaded file.
+const PLACEHOLDER: &str = "Images/missing.png";
 
 lazy_static! {
     static ref OEBPS: PathBuf = PathBuf::from("OEBPS");
 }
 
+/// Total size of the images before and after optimization
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImageStats {
+    pub original_bytes: u64,
+    pub optimized_bytes: u64,
+}
+
+/// Version of the generated package. Both versions include toc.ncx, EPUB 3 adds nav.xhtml.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EpubVersion {
+    #[default]
+    V2,
+    V3,
+}
+
+impl FromStr for EpubVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "2" => Ok(Self::V2),
+            "3" => Ok(Self::V3),
+            other => Err(format!(
+                "Unsupported epub version: {}. Supported versions: 2, 3",
+                other
+            )),
+        }
+    }
+}
+
 pub struct EpubBuilder<'a> {
     zip: ZipArchive,
     book: &'a Book,
     base_files_url: Url,
-    stylesheets: HashMap<Url, String>,
-    images: HashMap<Url, String>,
+    // ordered, so that the same book always gives the same epub
+    stylesheets: IndexMap<Url, String>,
+    image_urls: IndexSet<Url>,
+    // names of the downloaded images and stylesheet dependencies
+    assets: Assets,
     parser: Parser,
-    chapter_names: Vec<String>,
-    // image name
-    cover: String,
+    chapters: &'a [Chapter],
+    // chapter name and its manifest properties
+    chapter_names: Vec<(String, String)>,
+    // image url
+    cover: Option<Url>,
+    cover_chapter: Option<String>,
+    version: EpubVersion,
     kindle: bool,
+    image_policies: ImagePolicies,
+    progress: Option<ProgressCallback>,
+    image_stats: ImageStats,
+    modified: DateTime<Utc>,
 }
 
 impl<'a> EpubBuilder<'a> {
-    pub fn new(book: &'a Book, kindle: bool) -> Result<Self> {
+    pub fn new(book: &'a Book, kindle: bool, endpoints: &Endpoints) -> Result<Self> {
+        let modified = build_time(book);
         let mut epub = EpubBuilder {
-            zip: ZipArchive::new()?,
+            zip: ZipArchive::new(modified)?,
             book,
-            base_files_url: Url::parse(&format!(
-                "https://learning.oreilly.com/api/v2/epubs/urn:orm:book:{}/files/",
-                book.identifier
-            ))
-                .unwrap(),
+            base_files_url: endpoints.book_files(&book.identifier)?,
             kindle,
             parser: Parser::default_html(),
             stylesheets: Default::default(),
-            images: Default::default(),
+            image_urls: Default::default(),
+            assets: Default::default(),
+            chapters: Default::default(),
             chapter_names: Default::default(),
-            cover: Default::default(),
+            cover: None,
+            cover_chapter: None,
+            version: Default::default(),
+            image_policies: Default::default(),
+            progress: None,
+            image_stats: Default::default(),
+            modified,
         };
 
         epub.zip.write_file(
@@ -98,7 +157,29 @@ impl<'a> EpubBuilder<'a> {
         Ok(epub)
     }
 
-    fn rewrite_chapter_links(&self, old: &str) -> String {
+    /// Report download progress of images and stylesheets to `progress` instead of the
+    /// callback of the client
+    pub fn with_progress(&mut self, progress: ProgressCallback) -> &mut Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub fn with_version(&mut self, version: EpubVersion) -> &mut Self {
+        self.version = version;
+        self
+    }
+
+    pub fn with_image_policies(&mut self, image_policies: ImagePolicies) -> &mut Self {
+        self.image_policies = image_policies;
+        self
+    }
+
+    /// Image optimization results, available after [`EpubBuilder::generate`]
+    pub fn image_stats(&self) -> ImageStats {
+        self.image_stats
+    }
+
+    fn rewrite_chapter_links(&self, chapter: &Chapter, images: &HashSet<Url>, old: &str) -> String {
         // Url does not support relative urls, use dummy host to convert to absolute
         let abs_url = match Url::parse(old) {
             Err(ParseError::RelativeUrlWithoutBase) => {
@@ -121,9 +202,22 @@ impl<'a> EpubBuilder<'a> {
         // For images and html create a new path
         let new_path = match path.extension().and_then(OsStr::to_str) {
             Some("html") => path.with_extension(XHTML).to_str().map(str::to_string),
-            Some(ext) if ImageFormat::from_extension(ext).is_some() => path
-                .to_str()
-                .map(|filename| format!("../{}/{}", IMAGES, filename)),
+            Some(ext) if ImageFormat::from_extension(ext).is_some() => {
+                match self.image_url(chapter, images, old) {
+                    Some(url) => Some(match self.assets.get(&url) {
+                        Some(filename) => format!("../{}", filename),
+                        // Missing on the server, the book must not link to remote files
+                        None => format!("../{}", PLACEHOLDER),
+                    }),
+                    None => {
+                        warn!(
+                            "Image {} of {} is not in the chapter's images, keeping the link",
+                            old, chapter.meta.filename
+                        );
+                        None
+                    }
+                }
+            }
             _ => return old.to_string(),
         };
 
@@ -142,9 +236,38 @@ impl<'a> EpubBuilder<'a> {
         old.to_string()
     }
 
-    fn extract_chapter_content(&self, chapter_body: &str) -> Result<String> {
+    /// Url of an image linked from a chapter. Links are relative to the chapter or to the book
+    /// files, and as a last resort are matched to the chapter `images` by file name.
+    fn image_url(&self, chapter: &Chapter, images: &HashSet<Url>, link: &str) -> Option<Url> {
+        let resolved = [&chapter.meta.content_url, &self.base_files_url]
+            .into_iter()
+            .filter_map(|base| base.join(link).ok())
+            .map(|mut url| {
+                url.set_query(None);
+                url.set_fragment(None);
+                url
+            })
+            .find(|url| images.contains(url));
+        if resolved.is_some() {
+            return resolved;
+        }
+
+        let filename = link.split(['?', '#']).next()?.rsplit('/').next()?;
+        let mut same_name = images
+            .iter()
+            .filter(|url| url.path().rsplit('/').next() == Some(filename));
+        match (same_name.next(), same_name.next()) {
+            (Some(url), None) => Some(url.clone()),
+            _ => None,
+        }
+    }
+
+    fn extract_chapter_content(&self, chapter: &Chapter) -> Result<String> {
+        let chapter_body = &chapter.content;
         let document = self.parser.parse_string(chapter_body)?;
-        let rewritten = document.rewrite_links(|old| self.rewrite_chapter_links(old));
+        let images = self.extract_images(chapter)?.into_iter().collect();
+        let rewritten =
+            document.rewrite_links(|old| self.rewrite_chapter_links(chapter, &images, old));
         debug!("Links rewritten: {}", rewritten);
         // let stripped = document.strip_invalid_attributes();
         // warn!("Invalid attributes stripped: {}", stripped);
@@ -166,20 +289,13 @@ impl<'a> EpubBuilder<'a> {
         ))
     }
 
-    fn extract_images(&self, chapter: &Chapter) -> Result<Vec<(Url, String)>> {
+    fn extract_images(&self, chapter: &Chapter) -> Result<Vec<Url>> {
         let image_urls = chapter
             .meta
             .images
             .iter()
-            .map(|x| {
-                self.base_files_url.join(x).ok().and_then(|url| {
-                    PathBuf::from(url.path())
-                        .file_name()
-                        .and_then(OsStr::to_str)
-                        .map(|filename| (url, format!("{}/{}", IMAGES, filename)))
-                })
-            })
-            .collect::<Option<Vec<_>>>()
+            .map(|x| self.base_files_url.join(x))
+            .collect::<std::result::Result<Vec<_>, _>>()
             .context("Failed to join image url")?;
 
         Ok(image_urls)
@@ -204,10 +320,12 @@ impl<'a> EpubBuilder<'a> {
 
     fn add_chapter(&mut self, chapter: &Chapter) -> Result<()> {
         debug!("Processing {}", &chapter.meta.filename);
+        let body = self.extract_chapter_content(chapter)?;
         let chapter_xhtml = ChapterXhtml {
             styles: &self.stylesheets.values().collect(),
-            body: &self.extract_chapter_content(&chapter.content)?,
+            body: &body,
             should_support_kindle: self.kindle,
+            epub3: self.version == EpubVersion::V3,
         };
 
         let filename = format!("{}/{}", TEXT, chapter.meta.filename);
@@ -219,11 +337,27 @@ impl<'a> EpubBuilder<'a> {
                 .context("failed to render chapter xhtml")?
                 .as_bytes(),
         )?;
-        self.chapter_names.push(filename);
+        self.chapter_names
+            .push((filename, Self::chapter_properties(&body)));
 
         Ok(())
     }
 
+    /// EPUB 3 manifest properties of a chapter, readers use them to enable svg, mathml and
+    /// javascript support
+    fn chapter_properties(body: &str) -> String {
+        [
+            ("<svg", "svg"),
+            ("<math", "mathml"),
+            ("<script", "scripted"),
+        ]
+        .iter()
+        .filter(|(tag, _)| body.contains(tag))
+        .map(|(_, property)| *property)
+        .collect::<Vec<_>>()
+        .join(" ")
+    }
+
     pub fn chapters(&mut self, chapters: &'a [Chapter]) -> Result<&mut Self> {
         for chapter in chapters {
             let images = self.extract_images(chapter)?;
@@ -232,21 +366,22 @@ impl<'a> EpubBuilder<'a> {
                 .file_stem()
                 .and_then(OsStr::to_str)
             {
-                if images.len() > 0 {
+                if !images.is_empty() {
                     debug!("Found cover in {:?}", chapter.meta.filename);
-                    self.cover = images[0].1.clone();
+                    self.cover = Some(images[0].clone());
+                    self.cover_chapter = Some(format!("{}/{}", TEXT, chapter.meta.filename));
                 } else {
                     warn!("Cover chapter has no attached images, final book may not have a cover")
                 }
             }
 
-            self.images.extend(images);
+            self.image_urls.extend(images);
             self.extract_styles(chapter)?;
-
-            self.add_chapter(chapter)?;
         }
+        // Chapters are rendered once the images are downloaded and named
+        self.chapters = chapters;
 
-        info!("Found {} images", self.images.len());
+        info!("Found {} images", self.image_urls.len());
         info!("Found {} stylesheets", self.stylesheets.len());
         Ok(self)
     }
@@ -292,13 +427,31 @@ impl<'a> EpubBuilder<'a> {
         }
     }
 
-    fn optimize_image(&self, source_bytes: Bytes) -> (ImageFormat, Bytes) {
+    /// CPU heavy, run on the blocking thread pool. Returns the format of the resulting image,
+    /// `None` if the format is unknown and the image was left as is.
+    fn optimize_image(
+        kindle: bool,
+        policies: &ImagePolicies,
+        source_bytes: Bytes,
+    ) -> (Option<ImageFormat>, Bytes) {
         const KINDLE_WIDTH: u32 = 1072;
         const MIN_SIZE_TO_OPTIMIZE: usize = 60 * 1024;
         const IMAGE_QUALITY: u8 = 75;  // 1-100
 
-        let image_reader = ImageReader::new(Cursor::new(&source_bytes));
-        let original_format = image_reader.format().unwrap_or(ImageFormat::Jpeg);
+        let image_reader = match ImageReader::new(Cursor::new(&source_bytes)).with_guessed_format()
+        {
+            Ok(reader) => reader,
+            Err(_) => return (None, source_bytes),
+        };
+        let Some(original_format) = image_reader.format() else {
+            debug!("Unknown image format, skipping optimizations");
+            return (None, source_bytes);
+        };
+        let policy = policies.get(original_format);
+        if policy == ImagePolicy::Keep {
+            debug!("Keeping {:?} image as is", original_format);
+            return (Some(original_format), source_bytes);
+        }
 
         // Skip everything smaller than this
         if source_bytes.len() < MIN_SIZE_TO_OPTIMIZE {
@@ -306,15 +459,17 @@ impl<'a> EpubBuilder<'a> {
                 "File is too small ({}b), skipping optimizations",
                 source_bytes.len()
             );
-            return (original_format, source_bytes);
+            return (Some(original_format), source_bytes);
         }
-        let mut source_image = image_reader
-            .with_guessed_format()
-            .expect("Unknown image format")
-            .decode()
-            .expect("Unknown image format");
+        let mut source_image = match image_reader.decode() {
+            Ok(image) => image,
+            Err(err) => {
+                warn!("Failed to decode image: {}. Leaving unoptimized", err);
+                return (Some(original_format), source_bytes);
+            }
+        };
 
-        if self.kindle && source_image.width() > KINDLE_WIDTH {
+        if kindle && source_image.width() > KINDLE_WIDTH {
             debug!(
                 "Image is too big {}x{}, resizing",
                 source_image.width(),
@@ -330,16 +485,32 @@ impl<'a> EpubBuilder<'a> {
         }
 
         let mut result = Cursor::new(Vec::new());
-        let mut output_format = ImageFormat::Jpeg;
-
-        let encoding_result = if source_image.color().has_alpha() {
-            debug!("Image has alpha channel, saving as png");
-            output_format = ImageFormat::Png;
-            let encoder = PngEncoder::new_with_quality(&mut result, CompressionType::default(), image::codecs::png::FilterType::default());
-            source_image.write_with_encoder(encoder)
-        } else {
-            let encoder = JpegEncoder::new_with_quality(&mut result, IMAGE_QUALITY);
-            source_image.write_with_encoder(encoder)
+        let output_format = match policy {
+            ImagePolicy::SameFormat => original_format,
+            _ if source_image.color().has_alpha() => {
+                debug!("Image has alpha channel, saving as png");
+                ImageFormat::Png
+            }
+            _ => ImageFormat::Jpeg,
+        };
+
+        let encoding_result = match output_format {
+            ImageFormat::Png => {
+                let encoder = PngEncoder::new_with_quality(
+                    &mut result,
+                    CompressionType::default(),
+                    image::codecs::png::FilterType::default(),
+                );
+                source_image.write_with_encoder(encoder)
+            }
+            ImageFormat::Jpeg => {
+                let encoder = JpegEncoder::new_with_quality(&mut result, IMAGE_QUALITY);
+                source_image.write_with_encoder(encoder)
+            }
+            format => {
+                debug!("Can't encode {:?} images, leaving unoptimized", format);
+                return (Some(original_format), source_bytes);
+            }
         };
 
         if let Err(err) = encoding_result {
@@ -347,7 +518,7 @@ impl<'a> EpubBuilder<'a> {
                 "Failed to optimize image: {:#?}. Leaving unoptimized",
                 err.to_string()
             );
-            return (original_format, source_bytes);
+            return (Some(original_format), source_bytes);
         }
 
         let optimized = Bytes::copy_from_slice(result.get_ref());
@@ -358,7 +529,7 @@ impl<'a> EpubBuilder<'a> {
             (optimized.len() as f32 - source_bytes.len() as f32) / optimized.len() as f32 * 100.0
         );
 
-        (output_format, optimized)
+        (Some(output_format), optimized)
     }
 
     pub async fn generate<W: tokio::io::AsyncWrite + Unpin>(
@@ -366,31 +537,56 @@ impl<'a> EpubBuilder<'a> {
         to: W,
         client: &OreillyClient<Authenticated>,
     ) -> Result<()> {
-        // Unique urls != unique filenames
-        let images_count = self.images.len();
-        let unique_images = self.images.values().collect::<HashSet<&String>>().len();
-        if images_count != unique_images {
-            warn!("Images have non-unique names, some of them might get overwritten");
-        }
+        let progress = self.progress.clone().or_else(|| client.progress().cloned());
+        let tracker =
+            |phase, total| Tracker::start(progress.as_ref(), &self.book.identifier, phase, total);
 
-        info!("Downloading and optimizing {} images", self.images.len());
-        let mut image_mimetypes: Vec<(String, String)> = Vec::with_capacity(self.images.len());
-        let mut images_size_bytes_before = 0f32;
-        let mut images_size_bytes_after = 0f32;
-        for (url, bytes) in client.bulk_download_bytes(self.images.keys()).await? {
+        info!(
+            "Downloading and optimizing {} images",
+            self.image_urls.len()
+        );
+        let mut image_mimetypes: Vec<(String, String)> = Vec::with_capacity(self.image_urls.len());
+        let mut image_stats = ImageStats::default();
+        let images_tracker = tracker(Phase::Images, self.image_urls.len());
+        let mut images = client
+            .bulk_download_tracked(self.image_urls.iter(), &images_tracker)
+            .await?;
+        // Downloads finish in any order, the first url of identical images names the file
+        images.sort_by_key(|(url, _)| self.image_urls.get_index_of(*url));
+        for (url, bytes) in images {
+            if let Some(filename) = self.assets.duplicate(url, &bytes) {
+                debug!("Image {} is a duplicate of {}", url, filename);
+                continue;
+            }
             debug!("Optimizing image {}", url);
-            images_size_bytes_before += bytes.len() as f32;
-            let (extension, bytes) = self.optimize_image(bytes);
-            images_size_bytes_after += bytes.len() as f32;
-            let filename = self.images.get(url).unwrap().clone();
+            image_stats.original_bytes += bytes.len() as u64;
+            let kindle = self.kindle;
+            let policies = self.image_policies.clone();
+            let source = bytes.clone();
+            let (format, optimized) = tokio::task::spawn_blocking(move || {
+                Self::optimize_image(kindle, &policies, source)
+            })
+            .await
+            .context("image optimization failed")?;
+            image_stats.optimized_bytes += optimized.len() as u64;
 
+            // Converted images get the extension of the new format, links are rewritten to it
+            let extension = format.map(|format| images::extension(format, url));
+            let filename = self.assets.insert(IMAGES, url, &bytes, extension);
             self.zip
-                .write_file(OEBPS.as_path().join(&filename), &*bytes)?;
+                .write_file(OEBPS.as_path().join(&filename), &*optimized)?;
 
-            image_mimetypes.push((filename, format!("{:?}", extension).to_ascii_lowercase()));
+            let mimetype = match format {
+                Some(format) => format.to_mime_type(),
+                None => mime_guess::from_path(&filename)
+                    .first_raw()
+                    .unwrap_or("application/octet-stream"),
+            };
+            image_mimetypes.push((filename, mimetype.to_string()));
         }
-        images_size_bytes_before /= 1024.0 * 1024.0;
-        images_size_bytes_after /= 1024.0 * 1024.0;
+        self.image_stats = image_stats;
+        let images_size_bytes_before = image_stats.original_bytes as f32 / (1024.0 * 1024.0);
+        let images_size_bytes_after = image_stats.optimized_bytes as f32 / (1024.0 * 1024.0);
         info!(
             "Image optimization results - before: {:.1}mb, after: {:.1}mb, diff: {:.1}mb ({:.2}%)",
             images_size_bytes_before,
@@ -398,9 +594,21 @@ impl<'a> EpubBuilder<'a> {
             images_size_bytes_after - images_size_bytes_before,
             (images_size_bytes_after - images_size_bytes_before) / images_size_bytes_after * 100.0
         );
+        for chapter in self.chapters {
+            self.add_chapter(chapter)?;
+        }
+
         info!("Downloading {} css", self.stylesheets.len());
-        let mut css_dependencies = HashMap::new();
-        for (url, bytes) in client.bulk_download_bytes(self.stylesheets.keys()).await? {
+        let mut css_dependencies = IndexSet::new();
+        // Name, code and the url placeholders of every stylesheet, written once the
+        // dependencies are named
+        let mut stylesheets = Vec::with_capacity(self.stylesheets.len());
+        let stylesheets_tracker = tracker(Phase::Stylesheets, self.stylesheets.len());
+        let mut downloaded = client
+            .bulk_download_tracked(self.stylesheets.keys(), &stylesheets_tracker)
+            .await?;
+        downloaded.sort_by_key(|(url, _)| self.stylesheets.get_index_of(*url));
+        for (url, bytes) in downloaded {
             let mut stylesheet = StyleSheet::parse(
                 std::str::from_utf8(&bytes[..]).unwrap(),
                 ParserOptions::default(),
@@ -411,53 +619,83 @@ impl<'a> EpubBuilder<'a> {
                 Self::rewrite_css_rules(&mut stylesheet.rules);
             }
             stylesheet.minify(MinifyOptions::default()).unwrap();
-            let deps = stylesheet
+            // Urls are printed as placeholders, replaced with the file names below
+            let res = stylesheet
                 .to_css(PrinterOptions {
+                    minify: true,
                     analyze_dependencies: Some(DependencyOptions {
-                        remove_imports: true,
+                        remove_imports: false,
                     }),
                     ..PrinterOptions::default()
                 })
-                .unwrap()
-                .dependencies;
+                .expect("Failed to convert to css");
 
-            for dependency in deps.unwrap_or_default() {
+            let mut placeholders = Vec::new();
+            for dependency in res.dependencies.unwrap_or_default() {
                 match dependency {
-                    Dependency::Url(url) => {
-                        css_dependencies.insert(
-                            self.base_files_url
-                                .join(&url.url)
-                                .expect("Failed to build css deps url"),
-                            format!("{}/{}", STYLES, url.url),
-                        );
+                    Dependency::Url(dependency) => {
+                        let dependency_url = self
+                            .base_files_url
+                            .join(&dependency.url)
+                            .expect("Failed to build css deps url");
+                        css_dependencies.insert(dependency_url.clone());
+                        placeholders.push((dependency.placeholder, dependency_url));
                     }
                     Dependency::Import(import) => warn!("css import dependency: {:?}", import.url),
                 }
             }
+            stylesheets.push((self.stylesheets.get(url).unwrap(), res.code, placeholders));
+        }
+        // Chapters link every stylesheet, missing ones are written empty
+        for filename in self.stylesheets.values() {
+            if !stylesheets.iter().any(|(name, _, _)| *name == filename) {
+                stylesheets.push((filename, String::new(), Vec::new()));
+            }
+        }
 
-            let res = stylesheet
-                .to_css(PrinterOptions {
-                    minify: true,
-                    ..PrinterOptions::default()
-                })
-                .expect("Failed to convert to css");
+        info!("Downloading {} css dependencies", css_dependencies.len());
+        let dependencies_tracker = tracker(Phase::StylesheetDependencies, css_dependencies.len());
+        let mut dependencies = client
+            .bulk_download_tracked(css_dependencies.iter(), &dependencies_tracker)
+            .await?;
+        dependencies.sort_by_key(|(url, _)| css_dependencies.get_index_of(*url));
+        let mut css_deps = Vec::new();
+        for (url, bytes) in dependencies {
+            // Dependencies identical to an image or to another dependency point to that file
+            if self.assets.duplicate(url, &bytes).is_none() {
+                let filename = self.assets.insert(STYLES, url, &bytes, None);
+                self.zip
+                    .write_file(OEBPS.as_path().join(&filename), &bytes[..])?;
+                css_deps.push(filename);
+            }
+        }
 
-            self.zip.write_file(
-                OEBPS.as_path().join(self.stylesheets.get(url).unwrap()),
-                res.code.as_bytes(),
-            )?;
+        for (filename, mut code, placeholders) in stylesheets {
+            for (placeholder, url) in placeholders {
+                let dependency = match self.assets.get(&url) {
+                    Some(dependency) => format!("../{}", dependency),
+                    None => format!("../{}", PLACEHOLDER),
+                };
+                code = code.replace(&placeholder, &dependency);
+            }
+            self.zip
+                .write_file(OEBPS.as_path().join(filename), code.as_bytes())?;
         }
 
-        info!("Downloading {} css dependencies", css_dependencies.len());
-        for (url, bytes) in client.bulk_download_bytes(css_dependencies.keys()).await? {
-            self.zip.write_file(
-                OEBPS.as_path().join(css_dependencies.get(url).unwrap()),
-                &bytes[..],
-            )?;
+        if self
+            .image_urls
+            .iter()
+            .chain(&css_dependencies)
+            .any(|url| self.assets.get(url).is_none())
+        {
+            debug!("Adding a placeholder for the files missing on the server");
+            self.zip
+                .write_file(OEBPS.as_path().join(PLACEHOLDER), &*placeholder_image())?;
+            image_mimetypes.push((PLACEHOLDER.to_string(), "image/png".to_string()));
         }
 
         info!("Rendering OPF and generating final EPUB");
-        self.render_opf(&image_mimetypes, &css_dependencies.values().collect())?
+        self.render_opf(&image_mimetypes, &css_deps.iter().collect())?
             .zip
             .generate(to)
             .await?;
@@ -471,6 +709,7 @@ impl<'a> EpubBuilder<'a> {
         css_deps: &Vec<&String>,
     ) -> Result<&mut Self> {
         let content_opf = ContentOpf {
+            epub3: self.version == EpubVersion::V3,
             title: &self.book.title,
             description: &self.book.description,
             publishers: &self
@@ -484,7 +723,20 @@ impl<'a> EpubBuilder<'a> {
             issued: &self.book.issued,
             language: &self.book.language,
             isbn: &self.book.isbn,
-            cover_image: &self.cover,
+            identifier: &self.book.identifier,
+            fingerprint: &fingerprint(
+                self.book,
+                self.chapters,
+                self.kindle,
+                self.version,
+                &self.image_policies,
+            ),
+            modified: &self.modified.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            cover_image: self
+                .cover
+                .as_ref()
+                .and_then(|url| self.assets.get(url))
+                .map_or("", String::as_str),
             authors: &self.book.authors,
             subjects: &self.book.subjects,
             styles: &self.stylesheets.values().collect(),
@@ -504,40 +756,88 @@ impl<'a> EpubBuilder<'a> {
         Ok(self)
     }
 
+    /// Render the toc.ncx navPoints of `elements`, children first. Returns the depth of the
+    /// deepest element, the next play order and the rendered navPoints.
     fn parse_navpoints(
         elements: &[TocElement],
         mut order: usize,
         mut depth: usize,
-    ) -> (usize, usize, Vec<NavPoint>) {
-        let navpoints = elements
+    ) -> askama::Result<(usize, usize, Vec<String>)> {
+        let mut navpoints = Vec::with_capacity(elements.len());
+        for elem in elements {
+            let (child_depth, new_order, children) =
+                Self::parse_navpoints(&elem.children, order + 1, depth)?;
+            depth = depth.max(elem.depth).max(child_depth);
+
+            let navpoint = NavPoint {
+                id: if elem.fragment.is_empty() {
+                    &elem.id
+                } else {
+                    &elem.fragment
+                },
+                order,
+                children,
+                label: &elem.label,
+                url: format!("{}/{}", TEXT, elem.href),
+            };
+            navpoints.push(navpoint.render()?);
+            order = new_order;
+        }
+
+        Ok((depth, order, navpoints))
+    }
+
+    /// Render the nav.xhtml list items of `elements`, children first
+    fn nav_items(elements: &[TocElement]) -> askama::Result<Vec<String>> {
+        elements
             .iter()
             .map(|elem| {
-                let (child_depth, new_order, children) =
-                    Self::parse_navpoints(&elem.children, order + 1, depth);
-                depth = depth.max(elem.depth).max(child_depth);
-
-                let navpoint = NavPoint {
-                    id: if elem.fragment.is_empty() {
-                        &elem.id
-                    } else {
-                        &elem.fragment
-                    },
-                    order,
-                    children,
+                NavItem {
                     label: &elem.label,
                     url: format!("{}/{}", TEXT, elem.href),
-                };
-                order = new_order;
-                navpoint
+                    children: Self::nav_items(&elem.children)?,
+                }
+                .render()
             })
-            .collect();
+            .collect()
+    }
 
-        (depth, order, navpoints)
+    /// Render nav.xhtml, the EPUB 3 replacement of toc.ncx
+    fn nav(&mut self, toc: &[TocElement]) -> Result<()> {
+        let mut landmarks = Vec::new();
+        if let Some(cover) = &self.cover_chapter {
+            landmarks.push(("cover", "Cover", cover.clone()));
+        }
+        landmarks.push(("toc", "Table of Contents", "nav.xhtml#toc".to_string()));
+        // The first chapter that is not the cover
+        if let Some(start) = toc
+            .iter()
+            .map(|elem| format!("{}/{}", TEXT, elem.href))
+            .find(|url| url.split('#').next() != self.cover_chapter.as_deref())
+        {
+            landmarks.push(("bodymatter", "Start", start));
+        }
+
+        self.zip.write_file(
+            OEBPS.as_path().join("nav.xhtml"),
+            Nav {
+                title: &self.book.title,
+                language: &self.book.language,
+                items: &Self::nav_items(toc).context("failed to render nav.xhtml")?,
+                landmarks: &landmarks,
+            }
+            .render()
+            .context("failed to render nav.xhtml")?
+            .as_bytes(),
+        )?;
+
+        Ok(())
     }
 
-    // Render toc.ncx
+    // Render toc.ncx, and nav.xhtml for EPUB 3
     pub fn toc(&mut self, toc: &[TocElement]) -> Result<&mut Self> {
-        let (depth, _, navpoints) = Self::parse_navpoints(toc, 0, 0);
+        let (depth, _, navpoints) =
+            Self::parse_navpoints(toc, 0, 0).context("failed to render toc.ncx")?;
         self.zip.write_file(
             OEBPS.as_path().join("toc.ncx"),
             Toc {
@@ -559,7 +859,154 @@ impl<'a> EpubBuilder<'a> {
                 .context("failed to render chapter xhtml")?
                 .as_bytes(),
         )?;
+        if self.version == EpubVersion::V3 {
+            self.nav(toc)?;
+        }
 
         Ok(self)
     }
 }
+
+/// Modification time recorded in the epub. `SOURCE_DATE_EPOCH` if set, otherwise the issue date
+/// of the book, so that building the same book twice gives identical files.
+fn build_time(book: &Book) -> DateTime<Utc> {
+    if let Ok(epoch) = std::env::var("SOURCE_DATE_EPOCH") {
+        match epoch
+            .parse()
+            .ok()
+            .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+        {
+            Some(time) => return time,
+            None => warn!("Invalid SOURCE_DATE_EPOCH {:?}, ignoring", epoch),
+        }
+    }
+
+    book.issued
+        .get(..10)
+        .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|time| Utc.from_utc_datetime(&time))
+        // Earliest time zip files can store
+        .unwrap_or_else(|| Utc.with_ymd_and_hms(1980, 1, 1, 0, 0, 0).unwrap())
+}
+
+/// Transparent 1x1 png written as [`PLACEHOLDER`]
+fn placeholder_image() -> Vec<u8> {
+    let mut png = Cursor::new(Vec::new());
+    image::RgbaImage::new(1, 1)
+        .write_to(&mut png, ImageFormat::Png)
+        .expect("Failed to encode the placeholder image");
+    png.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(depth: usize, label: &str, href: &str, children: Vec<TocElement>) -> TocElement {
+        TocElement {
+            depth,
+            url: String::new(),
+            minutes_required: 0.0,
+            fragment: href
+                .split_once('#')
+                .map(|(_, f)| f.to_string())
+                .unwrap_or_default(),
+            filename: String::new(),
+            natural_key: Vec::new(),
+            label: label.to_string(),
+            full_path: String::new(),
+            href: href.to_string(),
+            id: label.to_lowercase().replace(' ', "-"),
+            media_type: String::new(),
+            children,
+        }
+    }
+
+    fn toc() -> Vec<TocElement> {
+        vec![
+            element(
+                1,
+                "Chapter 1",
+                "ch01.xhtml",
+                vec![element(
+                    2,
+                    "Section 1.1",
+                    "ch01.xhtml#s1",
+                    vec![element(3, "Part <1.1.1>", "ch01.xhtml#s11", vec![])],
+                )],
+            ),
+            element(1, "Chapter 2", "ch02.xhtml", vec![]),
+        ]
+    }
+
+    #[test]
+    fn nested_navpoints() {
+        let (depth, order, navpoints) = EpubBuilder::parse_navpoints(&toc(), 0, 0).unwrap();
+        assert_eq!((depth, order), (3, 4));
+        assert_eq!(navpoints.len(), 2);
+
+        let chapter = &navpoints[0];
+        let position = |text: &str| chapter.find(text).unwrap_or_else(|| panic!("{}", text));
+        assert!(position("playOrder=\"0\"") < position("playOrder=\"1\""));
+        assert!(position("playOrder=\"1\"") < position("playOrder=\"2\""));
+        assert!(chapter.contains("<content src=\"Text/ch01.xhtml#s11\"/>"));
+        assert!(chapter.contains("Part &lt;1.1.1&gt;"));
+        assert_eq!(chapter.matches("</navPoint>").count(), 3);
+        assert!(navpoints[1].contains("playOrder=\"3\""));
+    }
+
+    #[test]
+    fn nested_nav_items() {
+        let items = EpubBuilder::nav_items(&toc()).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].matches("<li>").count(), 3);
+        assert_eq!(items[0].matches("<ol>").count(), 2);
+        assert!(items[0].contains("<a href=\"Text/ch01.xhtml#s11\">Part &lt;1.1.1&gt;</a>"));
+        assert!(!items[1].contains("<ol>"));
+    }
+
+    /// The environment is shared by all tests, so every case runs in this one test
+    #[test]
+    fn build_times() {
+        let book = |issued: &str| -> Book {
+            let json = serde_json::json!({
+                "identifier": "1234",
+                "isbn": "9781234567897",
+                "cover": "https://learning.oreilly.com/library/cover/1234/",
+                "chapter_list": "",
+                "toc": "",
+                "flat_toc": "",
+                "title": "Title",
+                "source": "",
+                "pagecount": 1,
+                "authors": [],
+                "subjects": [],
+                "publishers": [],
+                "description": "",
+                "issued": issued,
+                "language": "en",
+            });
+            serde_json::from_str(&json.to_string()).unwrap()
+        };
+        let issued = book("2024-01-02T00:00:00Z");
+        let date = |y, m, d| Utc.with_ymd_and_hms(y, m, d, 0, 0, 0).unwrap();
+
+        std::env::remove_var("SOURCE_DATE_EPOCH");
+        assert_eq!(build_time(&issued), date(2024, 1, 2));
+        // Books without an issue date get the earliest time a zip file can store
+        assert_eq!(build_time(&book("")), date(1980, 1, 1));
+        assert_eq!(build_time(&book("unknown")), date(1980, 1, 1));
+
+        std::env::set_var("SOURCE_DATE_EPOCH", "1700000000");
+        let epoch = Utc.with_ymd_and_hms(2023, 11, 14, 22, 13, 20).unwrap();
+        assert_eq!(build_time(&issued), epoch);
+        assert_eq!(build_time(&book("")), epoch);
+
+        for invalid in ["", "yesterday", "-99999999999999999"] {
+            std::env::set_var("SOURCE_DATE_EPOCH", invalid);
+            assert_eq!(build_time(&issued), date(2024, 1, 2), "{}", invalid);
+        }
+        std::env::remove_var("SOURCE_DATE_EPOCH");
+    }
+}
@@ -0,0 +1,484 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{Cursor, Read},
+};
+
+use anyhow::Context;
+use libxml::{
+    parser::{Parser, ParserOptions},
+    tree::Document,
+};
+use zip::CompressionMethod;
+
+use crate::{epub::lxml::DocumentExt, error::Result};
+
+const MIMETYPE: &str = "application/epub+zip";
+const NCX_MEDIA_TYPE: &str = "application/x-dtbncx+xml";
+const XHTML_MEDIA_TYPE: &str = "application/xhtml+xml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Readers usually cope with it
+    Warning,
+    /// Readers or Send to Kindle may reject the book
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct Issue {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Problems found in a generated epub
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    pub issues: Vec<Issue>,
+}
+
+impl CheckReport {
+    fn warning(&mut self, message: String) {
+        self.issues.push(Issue {
+            severity: Severity::Warning,
+            message,
+        });
+    }
+
+    fn error(&mut self, message: String) {
+        self.issues.push(Issue {
+            severity: Severity::Error,
+            message,
+        });
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Issue> {
+        self.issues
+            .iter()
+            .filter(|issue| issue.severity == Severity::Warning)
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Issue> {
+        self.issues
+            .iter()
+            .filter(|issue| issue.severity == Severity::Error)
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+}
+
+/// Manifest `<item>` of content.opf
+struct Item {
+    id: String,
+    path: String,
+    media_type: String,
+}
+
+/// Check the structure of a finished epub: the mimetype entry, the manifest, the spine, the
+/// table of contents links and that every xhtml file is well-formed.
+///
+/// Only an unreadable archive is an `Err`, everything else ends up in the report.
+pub fn check(epub: &[u8]) -> Result<CheckReport> {
+    let mut report = CheckReport::default();
+    let mut archive =
+        ::zip::ZipArchive::new(Cursor::new(epub)).context("generated epub is not a zip")?;
+
+    check_mimetype(&mut archive, &mut report);
+
+    let files = archive
+        .file_names()
+        .filter(|name| !name.ends_with('/'))
+        .map(str::to_string)
+        .collect::<HashSet<_>>();
+
+    let Some(container) = read_entry(&mut archive, "META-INF/container.xml") else {
+        report.error("META-INF/container.xml is missing".to_string());
+        return Ok(report);
+    };
+    let opf_path = Parser::default()
+        .parse_string(container)
+        .ok()
+        .and_then(|container| {
+            container
+                .xpath("//*[local-name()='rootfile']")
+                .into_iter()
+                .find_map(|node| node.get_attribute("full-path"))
+        });
+    let Some(opf_path) = opf_path else {
+        report.error("META-INF/container.xml has no rootfile".to_string());
+        return Ok(report);
+    };
+    let Some(opf) = read_entry(&mut archive, &opf_path).and_then(parse_strict) else {
+        report.error(format!("{} is missing or not well-formed", opf_path));
+        return Ok(report);
+    };
+
+    // Ids are unique in the whole package document, not only in the manifest
+    let mut ids = HashSet::new();
+    for id in opf
+        .xpath("//*[@id]")
+        .into_iter()
+        .filter_map(|node| node.get_attribute("id"))
+    {
+        if !is_xml_name(&id) {
+            report.error(format!("{}: id {:?} is not a valid xml name", opf_path, id));
+        }
+        if !ids.insert(id.clone()) {
+            report.error(format!("{}: duplicate id {:?}", opf_path, id));
+        }
+    }
+
+    let items = opf
+        .xpath("//*[local-name()='manifest']/*[local-name()='item']")
+        .into_iter()
+        .map(|node| Item {
+            id: node.get_attribute("id").unwrap_or_default(),
+            path: resolve(&opf_path, &node.get_attribute("href").unwrap_or_default()),
+            media_type: node.get_attribute("media-type").unwrap_or_default(),
+        })
+        .collect::<Vec<_>>();
+    for item in &items {
+        if !files.contains(&item.path) {
+            report.error(format!(
+                "manifest item {:?} points to a missing file {}",
+                item.id, item.path
+            ));
+        }
+    }
+
+    let manifested = items
+        .iter()
+        .map(|item| item.path.as_str())
+        .collect::<HashSet<_>>();
+    let mut unlisted = files
+        .iter()
+        .filter(|file| {
+            *file != "mimetype"
+                && !file.starts_with("META-INF/")
+                && **file != opf_path
+                && !manifested.contains(file.as_str())
+        })
+        .collect::<Vec<_>>();
+    unlisted.sort();
+    for file in unlisted {
+        report.warning(format!("{} is not listed in the manifest", file));
+    }
+
+    let item_ids = items
+        .iter()
+        .map(|item| item.id.as_str())
+        .collect::<HashSet<_>>();
+    let spine = opf.xpath("//*[local-name()='spine']");
+    if spine.is_empty() {
+        report.error(format!("{} has no spine", opf_path));
+    }
+    for idref in opf
+        .xpath("//*[local-name()='spine']/*[local-name()='itemref']")
+        .into_iter()
+        .map(|node| node.get_attribute("idref").unwrap_or_default())
+    {
+        if !item_ids.contains(idref.as_str()) {
+            report.error(format!("spine references unknown item {:?}", idref));
+        }
+    }
+    if let Some(toc) = spine.first().and_then(|spine| spine.get_attribute("toc")) {
+        if !item_ids.contains(toc.as_str()) {
+            report.error(format!("spine toc references unknown item {:?}", toc));
+        }
+    }
+
+    // Element ids of every well-formed chapter, to resolve the fragments of toc links
+    let mut anchors = HashMap::new();
+    for item in items
+        .iter()
+        .filter(|item| item.media_type == XHTML_MEDIA_TYPE)
+    {
+        let Some(content) = read_entry(&mut archive, &item.path) else {
+            continue;
+        };
+        match parse_strict(content) {
+            Some(document) => {
+                let ids = document
+                    .xpath("//*[@id]")
+                    .into_iter()
+                    .filter_map(|node| node.get_attribute("id"))
+                    .collect::<HashSet<_>>();
+                anchors.insert(item.path.clone(), ids);
+            }
+            None => report.error(format!("{} is not well-formed xhtml", item.path)),
+        }
+    }
+
+    for ncx in items
+        .iter()
+        .filter(|item| item.media_type == NCX_MEDIA_TYPE)
+    {
+        let Some(document) = read_entry(&mut archive, &ncx.path).and_then(parse_strict) else {
+            report.error(format!("{} is missing or not well-formed", ncx.path));
+            continue;
+        };
+        for src in document
+            .xpath("//*[local-name()='content']")
+            .into_iter()
+            .filter_map(|node| node.get_attribute("src"))
+        {
+            let path = resolve(&ncx.path, &src);
+            match anchors.get(&path) {
+                _ if !files.contains(&path) => {
+                    report.error(format!("{} links to a missing file {}", ncx.path, src))
+                }
+                Some(ids) => match src.split_once('#') {
+                    Some((_, fragment)) if !ids.contains(fragment) => {
+                        report.error(format!("{} links to a missing anchor {}", ncx.path, src))
+                    }
+                    _ => {}
+                },
+                None => {}
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// `mimetype` must be the first entry, stored without compression, so that the file type can be
+/// detected from the first bytes of the archive
+fn check_mimetype(archive: &mut ::zip::ZipArchive<Cursor<&[u8]>>, report: &mut CheckReport) {
+    let Ok(mut first) = archive.by_index(0) else {
+        report.error("epub is empty".to_string());
+        return;
+    };
+    if first.name() != "mimetype" {
+        report.error(format!(
+            "the first entry is {}, expected mimetype",
+            first.name()
+        ));
+        return;
+    }
+    if first.compression() != CompressionMethod::Stored {
+        report.error("mimetype is compressed".to_string());
+    }
+    let mut content = String::new();
+    if first.read_to_string(&mut content).is_err() || content != MIMETYPE {
+        report.error(format!("mimetype must be exactly {:?}", MIMETYPE));
+    }
+}
+
+fn read_entry(archive: &mut ::zip::ZipArchive<Cursor<&[u8]>>, name: &str) -> Option<String> {
+    let mut content = String::new();
+    archive
+        .by_name(name)
+        .ok()?
+        .read_to_string(&mut content)
+        .ok()?;
+    Some(content)
+}
+
+/// The default parser recovers from errors, this one fails on anything that is not well-formed
+fn parse_strict(content: String) -> Option<Document> {
+    Parser::default()
+        .parse_string_with_options(
+            content,
+            ParserOptions {
+                recover: false,
+                ..Default::default()
+            },
+        )
+        .ok()
+}
+
+/// Archive path of `href` relative to the file at `base`, without the fragment
+fn resolve(base: &str, href: &str) -> String {
+    let href = href.split(['#', '?']).next().unwrap_or_default();
+    let mut components = base.split('/').collect::<Vec<_>>();
+    components.pop();
+    for component in href.split('/') {
+        match component {
+            "." | "" => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    components.join("/")
+}
+
+/// Whether `id` is a valid xml name without a colon, as required for ids
+fn is_xml_name(id: &str) -> bool {
+    let mut chars = id.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::{write::FileOptions, ZipWriter};
+
+    use super::*;
+
+    const CONTAINER: &str = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#;
+
+    const CHAPTER: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml"><head><title>Chapter</title></head>
+<body><h1 id="start">Chapter</h1></body></html>"#;
+
+    fn opf(manifest: &str, spine: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="id">
+  <metadata><dc:identifier xmlns:dc="http://purl.org/dc/elements/1.1/" id="id">1234</dc:identifier></metadata>
+  <manifest>
+    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+    {}
+  </manifest>
+  <spine toc="ncx">{}</spine>
+</package>"#,
+            manifest, spine
+        )
+    }
+
+    fn ncx(src: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+  <navMap>
+    <navPoint id="ch01" playOrder="1">
+      <navLabel><text>Chapter</text></navLabel>
+      <content src="{}"/>
+    </navPoint>
+  </navMap>
+</ncx>"#,
+            src
+        )
+    }
+
+    /// Epub with one chapter, `Text/ch01.xhtml`, with every file replaced by `files`
+    fn epub(files: &[(&str, &str)]) -> Vec<u8> {
+        let chapter_item =
+            r#"<item id="ch01" href="Text/ch01.xhtml" media-type="application/xhtml+xml"/>"#;
+        let mut entries = vec![
+            ("META-INF/container.xml", CONTAINER.to_string()),
+            (
+                "OEBPS/content.opf",
+                opf(chapter_item, r#"<itemref idref="ch01"/>"#),
+            ),
+            ("OEBPS/toc.ncx", ncx("Text/ch01.xhtml#start")),
+            ("OEBPS/Text/ch01.xhtml", CHAPTER.to_string()),
+        ];
+        for (name, content) in files {
+            entries.retain(|(entry, _)| entry != name);
+            if !content.is_empty() {
+                entries.push((name, content.to_string()));
+            }
+        }
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file("mimetype", stored).unwrap();
+        zip.write_all(MIMETYPE.as_bytes()).unwrap();
+        for (name, content) in entries {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn errors(epub: &[u8]) -> Vec<String> {
+        check(epub)
+            .unwrap()
+            .errors()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn valid_epub() {
+        let report = check(&epub(&[])).unwrap();
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+    }
+
+    #[test]
+    fn missing_manifest_file() {
+        let errors = errors(&epub(&[("OEBPS/Text/ch01.xhtml", "")]));
+        assert!(
+            errors.contains(
+                &"manifest item \"ch01\" points to a missing file OEBPS/Text/ch01.xhtml"
+                    .to_string()
+            ),
+            "{:?}",
+            errors
+        );
+    }
+
+    #[test]
+    fn bad_spine_idref() {
+        let chapter_item =
+            r#"<item id="ch01" href="Text/ch01.xhtml" media-type="application/xhtml+xml"/>"#;
+        let opf = opf(chapter_item, r#"<itemref idref="ch02"/>"#);
+        let errors = errors(&epub(&[("OEBPS/content.opf", &opf)]));
+        assert_eq!(errors, ["spine references unknown item \"ch02\""]);
+    }
+
+    #[test]
+    fn broken_toc_links() {
+        let ncx_path = "OEBPS/toc.ncx";
+        let issues = errors(&epub(&[(ncx_path, &ncx("Text/ch01.xhtml#end"))]));
+        assert_eq!(
+            issues,
+            ["OEBPS/toc.ncx links to a missing anchor Text/ch01.xhtml#end"]
+        );
+
+        let issues = errors(&epub(&[(ncx_path, &ncx("Text/ch02.xhtml"))]));
+        assert_eq!(
+            issues,
+            ["OEBPS/toc.ncx links to a missing file Text/ch02.xhtml"]
+        );
+    }
+
+    #[test]
+    fn malformed_xhtml() {
+        let chapter = CHAPTER.replace("</h1>", "");
+        let errors = errors(&epub(&[("OEBPS/Text/ch01.xhtml", &chapter)]));
+        assert_eq!(errors, ["OEBPS/Text/ch01.xhtml is not well-formed xhtml"]);
+    }
+
+    #[test]
+    fn compressed_mimetype_and_unlisted_files() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("mimetype", FileOptions::default()).unwrap();
+        zip.write_all(MIMETYPE.as_bytes()).unwrap();
+        let epub = zip.finish().unwrap().into_inner();
+        let errors = errors(&epub);
+        assert_eq!(
+            errors,
+            [
+                "mimetype is compressed",
+                "META-INF/container.xml is missing"
+            ]
+        );
+
+        let report = check(&self::epub(&[("OEBPS/notes.txt", "notes")])).unwrap();
+        let warnings = report
+            .warnings()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(warnings, ["OEBPS/notes.txt is not listed in the manifest"]);
+        assert!(!report.has_errors());
+    }
+}
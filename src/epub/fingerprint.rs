@@ -0,0 +1,110 @@
+use std::{fs::File, io::Read, path::Path};
+
+use anyhow::Context;
+use libxml::parser::Parser;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    epub::{builder::EpubVersion, images::ImagePolicies, lxml::DocumentExt},
+    error::{OrlyError, Result},
+    models::{Book, Chapter},
+};
+
+/// Name of the content.opf `<meta>` holding the id the book was downloaded with, must match
+/// templates/content.xml
+const BOOK_ID_META: &str = "orly:book-id";
+/// Name of the content.opf `<meta>` holding the [`fingerprint`] of the book
+const FINGERPRINT_META: &str = "orly:fingerprint";
+
+/// Hash of the book details, the chapters and the options the epub is built with. It changes
+/// when the book gains or loses chapters, when the text of a chapter is edited, e.g. when an
+/// Early Release is updated, or when the book is saved with other options.
+pub fn fingerprint<'a>(
+    book: &Book,
+    chapters: impl IntoIterator<Item = &'a Chapter>,
+    kindle: bool,
+    version: EpubVersion,
+    image_policies: &ImagePolicies,
+) -> String {
+    let mut hasher = Sha256::new();
+    for field in [
+        &book.identifier,
+        &book.isbn,
+        &book.title,
+        &book.issued,
+        &book.pagecount.to_string(),
+    ] {
+        hasher.update(field.as_bytes());
+        hasher.update([0]);
+    }
+    for chapter in chapters {
+        let meta = &chapter.meta;
+        hasher.update(meta.filename.as_bytes());
+        hasher.update([0]);
+        hasher.update(meta.content_url.as_str().as_bytes());
+        hasher.update([0]);
+        hasher.update(Sha256::digest(chapter.content.as_bytes()));
+        for image in &meta.images {
+            hasher.update(image.as_bytes());
+            hasher.update([0]);
+        }
+        for stylesheet in &meta.stylesheets {
+            hasher.update(stylesheet.full_path.as_bytes());
+            hasher.update([0]);
+        }
+    }
+    let options = format!("{} {:?} {}", kindle, version, image_policies.canonical());
+    hasher.update(options.as_bytes());
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Metadata orly recorded in a previously saved epub
+#[derive(Debug, Default)]
+pub struct StoredMetadata {
+    pub book_id: Option<String>,
+    pub fingerprint: Option<String>,
+}
+
+impl StoredMetadata {
+    /// Read the metadata of an epub. Both fields are empty for epubs saved by older versions.
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("failed to open {:?}", path))?;
+        let mut archive =
+            ::zip::ZipArchive::new(file).with_context(|| format!("{:?} is not an epub", path))?;
+
+        let container = read_entry(&mut archive, "META-INF/container.xml")?;
+        let container = Parser::default().parse_string(container)?;
+        let opf_path = container
+            .xpath("//*[local-name()='rootfile']")
+            .into_iter()
+            .find_map(|node| node.get_attribute("full-path"))
+            .ok_or_else(|| OrlyError::ParseError("container.xml has no rootfile".to_string()))?;
+
+        let opf = Parser::default().parse_string(read_entry(&mut archive, &opf_path)?)?;
+        let meta = |name: &str| {
+            opf.xpath(&format!("//*[local-name()='meta'][@name='{}']", name))
+                .into_iter()
+                .find_map(|node| node.get_attribute("content"))
+        };
+
+        Ok(Self {
+            book_id: meta(BOOK_ID_META),
+            fingerprint: meta(FINGERPRINT_META),
+        })
+    }
+}
+
+fn read_entry(archive: &mut ::zip::ZipArchive<File>, name: &str) -> Result<String> {
+    let mut content = String::new();
+    archive
+        .by_name(name)
+        .with_context(|| format!("epub has no {}", name))?
+        .read_to_string(&mut content)
+        .with_context(|| format!("failed to read {} from the epub", name))?;
+    Ok(content)
+}
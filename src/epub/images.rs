@@ -0,0 +1,159 @@
+use std::{collections::HashMap, path::Path, str::FromStr};
+
+use image::ImageFormat;
+use reqwest::Url;
+
+/// How large images of a format are optimized
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImagePolicy {
+    /// Re-encode as jpeg, or as png if the image has transparency
+    #[default]
+    Auto,
+    /// Re-encode in the original format, e.g. to keep screenshots of code sharp. Formats orly
+    /// can't encode are kept as is.
+    SameFormat,
+    /// Keep the original file
+    Keep,
+}
+
+impl FromStr for ImagePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "same-format" => Ok(Self::SameFormat),
+            "keep" => Ok(Self::Keep),
+            other => Err(format!(
+                "Unsupported image policy: {}. Supported policies: auto, same-format, keep",
+                other
+            )),
+        }
+    }
+}
+
+/// `FORMAT=POLICY` command line rule, e.g. `png=same-format`
+#[derive(Debug, Clone, Copy)]
+pub struct ImagePolicyRule {
+    pub format: ImageFormat,
+    pub policy: ImagePolicy,
+}
+
+impl FromStr for ImagePolicyRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (format, policy) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected FORMAT=POLICY, e.g. png=same-format, got {}", s))?;
+        let format = ImageFormat::from_extension(format.to_ascii_lowercase())
+            .ok_or_else(|| format!("Unsupported image format: {}", format))?;
+
+        Ok(Self {
+            format,
+            policy: policy.parse()?,
+        })
+    }
+}
+
+/// Policies of the formats, [`ImagePolicy::Auto`] for formats without one
+#[derive(Debug, Clone, Default)]
+pub struct ImagePolicies(HashMap<ImageFormat, ImagePolicy>);
+
+impl ImagePolicies {
+    pub fn get(&self, format: ImageFormat) -> ImagePolicy {
+        self.0.get(&format).copied().unwrap_or_default()
+    }
+
+    /// Same text for the same policies, whatever order the rules were given in
+    pub(crate) fn canonical(&self) -> String {
+        let mut rules = self
+            .0
+            .iter()
+            .filter(|(_, policy)| **policy != ImagePolicy::Auto)
+            .map(|(format, policy)| format!("{:?}={:?}", format, policy))
+            .collect::<Vec<_>>();
+        rules.sort();
+        rules.join(",")
+    }
+}
+
+impl FromIterator<ImagePolicyRule> for ImagePolicies {
+    fn from_iter<T: IntoIterator<Item = ImagePolicyRule>>(rules: T) -> Self {
+        Self(
+            rules
+                .into_iter()
+                .map(|rule| (rule.format, rule.policy))
+                .collect(),
+        )
+    }
+}
+
+/// Extension of an image saved in `format`. The extension of the url is kept if it matches
+/// the format, e.g. `.jpeg` is not renamed to `.jpg`.
+pub(crate) fn extension(format: ImageFormat, url: &Url) -> &'static str {
+    let extensions = format.extensions_str();
+    Path::new(url.path())
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(|extension| {
+            extensions
+                .iter()
+                .find(|known| known.eq_ignore_ascii_case(extension))
+        })
+        .or(extensions.first())
+        .copied()
+        .unwrap_or("bin")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules() {
+        let rule = "PNG=same-format".parse::<ImagePolicyRule>().unwrap();
+        assert_eq!(rule.format, ImageFormat::Png);
+        assert_eq!(rule.policy, ImagePolicy::SameFormat);
+        let rule = "jpeg=keep".parse::<ImagePolicyRule>().unwrap();
+        assert_eq!(rule.format, ImageFormat::Jpeg);
+        assert_eq!(rule.policy, ImagePolicy::Keep);
+
+        for invalid in [
+            "png",
+            "png:keep",
+            "=keep",
+            "png=",
+            "svgz=keep",
+            "png=shrink",
+        ] {
+            assert!(invalid.parse::<ImagePolicyRule>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn policies() {
+        let policies = ["gif=keep", "png=same-format", "png=keep"]
+            .iter()
+            .map(|rule| rule.parse::<ImagePolicyRule>().unwrap())
+            .collect::<ImagePolicies>();
+        // The last rule of a format wins
+        assert_eq!(policies.get(ImageFormat::Png), ImagePolicy::Keep);
+        assert_eq!(policies.get(ImageFormat::Jpeg), ImagePolicy::Auto);
+        assert_eq!(policies.canonical(), "Gif=Keep,Png=Keep");
+    }
+
+    #[test]
+    fn extensions() {
+        let url = |path: &str| {
+            Url::parse("https://example.com/")
+                .unwrap()
+                .join(path)
+                .unwrap()
+        };
+        assert_eq!(extension(ImageFormat::Jpeg, &url("fig.png")), "jpg");
+        assert_eq!(extension(ImageFormat::Jpeg, &url("fig.JPEG")), "jpeg");
+        assert_eq!(extension(ImageFormat::Png, &url("fig.png?v=1")), "png");
+        assert_eq!(extension(ImageFormat::Png, &url("fig")), "png");
+    }
+}
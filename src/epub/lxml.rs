@@ -27,6 +27,7 @@ pub(crate) trait DocumentExt {
     fn evaluate_xpath(&self, query: &str) -> Option<Object>;
     fn node_to_string_with_options<T: NodeType>(&self, node: &T, options: SaveOptions) -> String;
 
+    #[allow(dead_code)]
     fn node_to_string<T: NodeType>(&self, node: &T) -> String {
         self.node_to_string_with_options(node, Default::default())
     }
@@ -44,6 +45,7 @@ pub(crate) trait DocumentExt {
         }
     }
 
+    #[allow(dead_code)]
     fn strip_invalid_attributes(&self) -> usize {
         let mut stripped = 0;
         let invalid_attrs = ["data-", "epub:type"];
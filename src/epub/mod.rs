@@ -1,3 +1,7 @@
+mod assets;
 pub mod builder;
+pub mod check;
+pub mod fingerprint;
+pub mod images;
 mod lxml;
 mod zip;
@@ -7,6 +7,7 @@ use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 use crate::error::Result;
 use anyhow::Context;
+use chrono::{DateTime, Datelike, Timelike, Utc};
 use zip::{
     write::{FileOptions, ZipWriter},
     CompressionMethod,
@@ -14,6 +15,8 @@ use zip::{
 
 pub struct ZipArchive {
     writer: ZipWriter<Cursor<Vec<u8>>>,
+    // options of every entry, with a fixed modification time instead of the current one
+    options: FileOptions,
 }
 
 impl fmt::Debug for ZipArchive {
@@ -23,21 +26,34 @@ impl fmt::Debug for ZipArchive {
 }
 
 impl ZipArchive {
-    pub fn new() -> Result<Self> {
+    /// All entries are stored with `modified` as the modification time, times zip files can't
+    /// store are replaced with 1980-01-01
+    pub fn new(modified: DateTime<Utc>) -> Result<Self> {
         let mut writer = ZipWriter::new(Cursor::new(vec![]));
         writer.set_comment(""); // Fix issues with some readers
 
+        let options = FileOptions::default().last_modified_time(
+            zip::DateTime::from_date_and_time(
+                modified.year().try_into().unwrap_or_default(),
+                modified.month() as u8,
+                modified.day() as u8,
+                modified.hour() as u8,
+                modified.minute() as u8,
+                modified.second() as u8,
+            )
+            .unwrap_or_default(),
+        );
         writer
             .start_file(
                 "mimetype",
-                FileOptions::default().compression_method(CompressionMethod::Stored),
+                options.compression_method(CompressionMethod::Stored),
             )
             .context("could not create mimetype in epub")?;
         writer
             .write(b"application/epub+zip")
             .context("could not write mimetype in epub")?;
 
-        Ok(ZipArchive { writer })
+        Ok(ZipArchive { writer, options })
     }
 
     pub fn write_file<P: AsRef<Path>, R: Read>(&mut self, path: P, mut content: R) -> Result<()> {
@@ -46,9 +62,8 @@ impl ZipArchive {
             // Path names should not use backspaces in zip files
             file = file.replace('\\', "/");
         }
-        let options = FileOptions::default();
         self.writer
-            .start_file(file.clone(), options)
+            .start_file(file.clone(), self.options)
             .with_context(|| format!("could not create file '{}' in epub", file))?;
         io::copy(&mut content, &mut self.writer)
             .with_context(|| format!("could not write file '{}' in epub", file))?;
@@ -1,9 +1,49 @@
+use std::time::Duration;
+
+use reqwest::{StatusCode, Url};
 use thiserror::Error;
 
+use crate::http::retry;
+
 #[derive(Error, Debug)]
 pub enum OrlyError {
     #[error("Request failed: {0}")]
     HttpRequest(#[from] reqwest::Error),
+    #[error("Not signed in or the session expired, sign in again ({url})")]
+    Unauthorized {
+        url: String,
+        book_id: Option<String>,
+    },
+    #[error("{}", forbidden_message(.url, .book_id))]
+    Forbidden {
+        url: String,
+        book_id: Option<String>,
+    },
+    #[error("{}", not_found_message(.url, .book_id))]
+    NotFound {
+        url: String,
+        book_id: Option<String>,
+    },
+    #[error("Too many requests, try again later ({url})")]
+    Throttled {
+        url: String,
+        book_id: Option<String>,
+        retry_after: Option<Duration>,
+    },
+    #[error("Server error {status}, try again later ({url})")]
+    ServerError {
+        status: StatusCode,
+        url: String,
+        book_id: Option<String>,
+    },
+    #[error("Unexpected response {status} ({url})")]
+    UnexpectedStatus {
+        status: StatusCode,
+        url: String,
+        book_id: Option<String>,
+    },
+    #[error("Failed to parse response: {0}")]
+    JsonParseError(#[from] serde_json::Error),
     #[error("Failed to parse xml/html: {0}")]
     XmlParseError(#[from] libxml::parser::XmlParseError),
     #[error("Failed to parse xml/html: {0}")]
@@ -12,12 +52,135 @@ pub enum OrlyError {
     XpathError(()),
     #[error("Authentication failure: {0}")]
     AuthenticationFailed(String),
+    #[error("Session expired, sign in with credentials or a cookie")]
+    SessionExpired,
+    #[error("No credentials provided, sign in with credentials or a cookie")]
+    NoCredentials,
     #[error("Subscription expired")]
     SubscriptionExpired,
     #[error("Password login is not supported for account {0}")]
     PasswordLoginUnsupported(String),
+    #[error("Invalid book id, url or ISBN: {0}")]
+    InvalidBookId(String),
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("Invalid template {0}")]
+    InvalidTemplate(String),
+    #[error("Invalid epub: {0}")]
+    InvalidEpub(String),
+    #[error("Cancelled")]
+    Cancelled,
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
+fn forbidden_message(url: &str, book_id: &Option<String>) -> String {
+    match book_id {
+        Some(book_id) => format!(
+            "No access to book {}, your subscription might not include this title ({})",
+            book_id, url
+        ),
+        None => format!("Access denied ({})", url),
+    }
+}
+
+fn not_found_message(url: &str, book_id: &Option<String>) -> String {
+    match book_id {
+        Some(book_id) => format!("Book {} not found, check the id ({})", book_id, url),
+        None => format!("Not found ({})", url),
+    }
+}
+
+impl OrlyError {
+    /// Classify a non-success response
+    pub(crate) fn from_status(
+        status: StatusCode,
+        url: &Url,
+        retry_after: Option<Duration>,
+    ) -> Self {
+        let url = url.to_string();
+        let book_id = None;
+        match status {
+            StatusCode::UNAUTHORIZED => Self::Unauthorized { url, book_id },
+            StatusCode::FORBIDDEN => Self::Forbidden { url, book_id },
+            StatusCode::NOT_FOUND => Self::NotFound { url, book_id },
+            StatusCode::TOO_MANY_REQUESTS => Self::Throttled {
+                url,
+                book_id,
+                retry_after,
+            },
+            status if status.is_server_error() => Self::ServerError {
+                status,
+                url,
+                book_id,
+            },
+            status => Self::UnexpectedStatus {
+                status,
+                url,
+                book_id,
+            },
+        }
+    }
+
+    /// Attach the id of the book the failed request belongs to
+    pub fn with_book_id(mut self, id: &str) -> Self {
+        match &mut self {
+            Self::Unauthorized { book_id, .. }
+            | Self::Forbidden { book_id, .. }
+            | Self::NotFound { book_id, .. }
+            | Self::Throttled { book_id, .. }
+            | Self::ServerError { book_id, .. }
+            | Self::UnexpectedStatus { book_id, .. } => *book_id = Some(id.to_string()),
+            _ => {}
+        }
+        self
+    }
+
+    /// Attach the id of the book to the failure of a request for a part of it, e.g. a chapter.
+    /// A missing part does not mean that the book is missing, not found errors are kept as is.
+    pub fn within_book(self, id: &str) -> Self {
+        match self {
+            Self::NotFound { .. } => self,
+            _ => self.with_book_id(id),
+        }
+    }
+
+    /// Whether the same request might succeed if repeated
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::HttpRequest(err) => retry::is_transient_error(err),
+            Self::Throttled { .. } => true,
+            Self::ServerError { status, .. } | Self::UnexpectedStatus { status, .. } => {
+                retry::is_transient_status(*status)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether signing in again or renewing the subscription is required
+    pub fn is_authentication(&self) -> bool {
+        matches!(
+            self,
+            Self::Unauthorized { .. }
+                | Self::AuthenticationFailed(_)
+                | Self::SessionExpired
+                | Self::NoCredentials
+                | Self::SubscriptionExpired
+                | Self::PasswordLoginUnsupported(_)
+        )
+    }
+
+    /// Whether the server asked us to slow down
+    pub fn is_throttled(&self) -> bool {
+        matches!(
+            self,
+            Self::Throttled { .. }
+                | Self::ServerError {
+                    status: StatusCode::SERVICE_UNAVAILABLE,
+                    ..
+                }
+        )
+    }
+}
+
 pub type Result<T> = anyhow::Result<T, OrlyError>;
@@ -0,0 +1,264 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use log::trace;
+use reqwest::{
+    header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    RequestBuilder, Url,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+
+use crate::{error::Result, redact::Redacted};
+
+/// How long entries the server gave no validators for are used without downloading them again
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// On-disk cache of downloaded files keyed by url.
+///
+/// Every entry is stored as two files named after the sha256 of the url: the raw body and a
+/// small json file with the validators used to revalidate the entry on the next run.
+pub struct Cache {
+    dir: PathBuf,
+    max_age: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Metadata {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// Seconds since the unix epoch, entries of older versions don't have it
+    #[serde(default)]
+    stored_at: Option<u64>,
+}
+
+pub(crate) struct CacheEntry {
+    pub body: Bytes,
+    metadata: Metadata,
+}
+
+impl CacheEntry {
+    /// Whether the server gave us a way to check if the entry is still fresh
+    pub fn can_revalidate(&self) -> bool {
+        self.metadata.etag.is_some() || self.metadata.last_modified.is_some()
+    }
+
+    /// Whether the entry was stored less than `max_age` ago
+    pub fn is_fresh(&self, max_age: Duration) -> bool {
+        let age = self
+            .metadata
+            .stored_at
+            .and_then(|stored_at| now().checked_sub(stored_at));
+        age.is_some_and(|age| Duration::from_secs(age) < max_age)
+    }
+
+    /// Turn the request into a conditional one, the server replies with 304 if the entry is
+    /// still fresh
+    pub fn revalidate(&self, mut request: RequestBuilder) -> RequestBuilder {
+        if let Some(etag) = &self.metadata.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &self.metadata.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        request
+    }
+}
+
+impl Cache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create cache directory {:?}", dir))?;
+        Ok(Self {
+            dir,
+            max_age: DEFAULT_MAX_AGE,
+        })
+    }
+
+    /// Entries without an `ETag` or `Last-Modified` header are downloaded again once they are
+    /// older than `max_age`, one day by default
+    pub fn with_max_age(self, max_age: Duration) -> Self {
+        Self { max_age, ..self }
+    }
+
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    /// Platform specific user cache directory, e.g. `~/.cache/orly` on linux
+    pub fn default_dir() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("orly"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn entry_paths(&self, url: &Url) -> (PathBuf, PathBuf) {
+        let key = format!("{:x}", Sha256::digest(url.as_str().as_bytes()));
+        (
+            self.dir.join(format!("{}.body", key)),
+            self.dir.join(format!("{}.json", key)),
+        )
+    }
+
+    /// Look up the url. Missing or unreadable entries are treated as a cache miss.
+    pub(crate) async fn get(&self, url: &Url) -> Option<CacheEntry> {
+        let (body_path, metadata_path) = self.entry_paths(url);
+
+        let metadata = fs::read(&metadata_path).await.ok()?;
+        let metadata = match serde_json::from_slice::<Metadata>(&metadata) {
+            Ok(metadata) if metadata.url == url.as_str() => metadata,
+            _ => {
+                trace!("Ignoring invalid cache entry for {}", Redacted(url));
+                return None;
+            }
+        };
+        let body = fs::read(&body_path).await.ok()?;
+
+        Some(CacheEntry {
+            body: body.into(),
+            metadata,
+        })
+    }
+
+    pub(crate) async fn put(&self, url: &Url, headers: &HeaderMap, body: &[u8]) -> Result<()> {
+        let (body_path, metadata_path) = self.entry_paths(url);
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let metadata = Metadata {
+            url: url.to_string(),
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+            stored_at: Some(now()),
+        };
+
+        // Metadata is written last, an entry without it is never read
+        let _ = fs::remove_file(&metadata_path).await;
+        write_atomic(&body_path, body).await?;
+        write_atomic(&metadata_path, &serde_json::to_vec(&metadata)?).await?;
+
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+/// Write to a temporary file first so that an interrupted run never leaves a truncated entry.
+/// Every write gets its own temporary file, concurrent writes of the same path don't clash.
+pub(crate) async fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let path = path.to_path_buf();
+    let content = content.to_vec();
+    tokio::task::spawn_blocking(move || {
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)
+            .with_context(|| format!("failed to create a temporary file in {:?}", dir))?;
+        tmp.write_all(&content)
+            .with_context(|| format!("failed to write {:?}", tmp.path()))?;
+        tmp.persist(&path)
+            .with_context(|| format!("failed to write {:?}", path))?;
+        Ok(())
+    })
+    .await
+    .context("failed to write the file")?
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn url(path: &str) -> Url {
+        Url::parse("https://learning.oreilly.com/")
+            .unwrap()
+            .join(path)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path()).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+
+        assert!(cache.get(&url("a.png")).await.is_none());
+        cache.put(&url("a.png"), &headers, b"image").await.unwrap();
+
+        let entry = cache.get(&url("a.png")).await.unwrap();
+        assert_eq!(&entry.body[..], b"image");
+        assert!(entry.can_revalidate());
+        assert!(cache.get(&url("b.png")).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn entries_without_validators_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path()).unwrap();
+        cache
+            .put(&url("ch01.html"), &HeaderMap::new(), b"chapter")
+            .await
+            .unwrap();
+
+        let entry = cache.get(&url("ch01.html")).await.unwrap();
+        assert!(!entry.can_revalidate());
+        assert!(entry.is_fresh(cache.max_age()));
+        assert!(!entry.is_fresh(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn entries_of_older_versions_are_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path()).unwrap();
+        let (body_path, metadata_path) = cache.entry_paths(&url("a.png"));
+        std::fs::write(body_path, b"image").unwrap();
+        std::fs::write(
+            metadata_path,
+            br#"{"url":"https://learning.oreilly.com/a.png","etag":null,"last_modified":null}"#,
+        )
+        .unwrap();
+
+        let entry = cache.get(&url("a.png")).await.unwrap();
+        assert!(!entry.is_fresh(cache.max_age()));
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_of_the_same_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("entry.body");
+        let contents = (0..16)
+            .map(|i| format!("content {}", i))
+            .collect::<Vec<_>>();
+        let writes = contents
+            .iter()
+            .map(|content| write_atomic(&path, content.as_bytes()))
+            .collect::<Vec<_>>();
+        for result in futures::future::join_all(writes).await {
+            result.unwrap();
+        }
+
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .starts_with("content "));
+        // No temporary files are left behind
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Ignore throttling signals for this long after shrinking the concurrency. Requests that were
+/// already in flight usually get throttled too and should not shrink the limit any further.
+const DECREASE_COOLDOWN: Duration = Duration::from_secs(2);
+
+/// Client-wide request limiter.
+///
+/// Combines an optional token bucket capping the number of requests per second with an AIMD
+/// concurrency limit: the number of parallel requests is halved every time the server throttles
+/// us and grows back by one after a run of successful requests.
+pub struct Limiter {
+    max_concurrency: usize,
+    semaphore: Semaphore,
+    /// Permits that should be dropped instead of returned to the semaphore
+    debt: AtomicUsize,
+    state: Mutex<State>,
+    bucket: Option<Mutex<TokenBucket>>,
+}
+
+struct State {
+    limit: usize,
+    successes: usize,
+    last_decrease: Option<Instant>,
+}
+
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Take a token and return how long the caller has to wait for it. Tokens are reserved
+    /// upfront so concurrent callers queue up instead of racing for the next token.
+    fn reserve(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+        self.tokens -= 1.0;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+pub(crate) struct Permit<'a> {
+    limiter: &'a Limiter,
+    permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            if self.limiter.take_debt() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+impl Limiter {
+    pub fn new(max_concurrency: usize, requests_per_second: Option<f64>) -> Self {
+        let max_concurrency = max_concurrency.max(1);
+        Self {
+            max_concurrency,
+            semaphore: Semaphore::new(max_concurrency),
+            debt: AtomicUsize::new(0),
+            state: Mutex::new(State {
+                limit: max_concurrency,
+                successes: 0,
+                last_decrease: None,
+            }),
+            bucket: requests_per_second.filter(|rate| *rate > 0.0).map(|rate| {
+                let burst = rate.max(1.0);
+                Mutex::new(TokenBucket {
+                    rate,
+                    burst,
+                    tokens: burst,
+                    last_refill: Instant::now(),
+                })
+            }),
+        }
+    }
+
+    /// Current number of requests allowed to run in parallel
+    pub fn concurrency(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    pub fn max_concurrency(&self) -> usize {
+        self.max_concurrency
+    }
+
+    /// Wait until both the rate limit and the concurrency limit allow another request
+    ///
+    /// The concurrency permit is taken first, so requests queued behind it don't use up the
+    /// tokens of the rate limit while they wait.
+    pub(crate) async fn acquire(&self) -> Permit<'_> {
+        let permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("limiter semaphore is never closed");
+
+        if let Some(bucket) = &self.bucket {
+            let wait = bucket.lock().unwrap().reserve();
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+        }
+
+        Permit {
+            limiter: self,
+            permit: Some(permit),
+        }
+    }
+
+    /// Additive increase: grow the limit by one after `limit` successful requests in a row
+    pub(crate) fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.limit >= self.max_concurrency {
+            return;
+        }
+
+        state.successes += 1;
+        if state.successes < state.limit {
+            return;
+        }
+
+        state.successes = 0;
+        state.limit += 1;
+        // Cancel a pending decrease first, otherwise hand out a new permit
+        if !self.take_debt() {
+            self.semaphore.add_permits(1);
+        }
+        debug!("Increasing concurrency to {}", state.limit);
+    }
+
+    /// Multiplicative decrease: halve the limit when the server starts throttling
+    pub(crate) fn record_throttled(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.successes = 0;
+
+        if state
+            .last_decrease
+            .is_some_and(|at| at.elapsed() < DECREASE_COOLDOWN)
+        {
+            return;
+        }
+
+        let new_limit = (state.limit / 2).max(1);
+        let removed = state.limit - new_limit;
+        if removed == 0 {
+            return;
+        }
+
+        state.limit = new_limit;
+        state.last_decrease = Some(Instant::now());
+
+        // Take idle permits right away, the rest is collected as in-flight requests finish
+        let mut pending = removed;
+        while pending > 0 {
+            match self.semaphore.try_acquire() {
+                Ok(permit) => {
+                    permit.forget();
+                    pending -= 1;
+                }
+                Err(_) => break,
+            }
+        }
+        self.debt.fetch_add(pending, Ordering::SeqCst);
+
+        warn!(
+            "Server is throttling requests, reducing concurrency to {}",
+            new_limit
+        );
+    }
+
+    fn take_debt(&self) -> bool {
+        self.debt
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |d| d.checked_sub(1))
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn tokens(limiter: &Limiter) -> f64 {
+        limiter.bucket.as_ref().unwrap().lock().unwrap().tokens
+    }
+
+    #[tokio::test]
+    async fn queued_requests_keep_their_tokens() {
+        let limiter = Arc::new(Limiter::new(1, Some(1.0)));
+        let permit = limiter.acquire().await;
+        assert!(tokens(&limiter) < 0.1);
+
+        let waiters = (0..3)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move {
+                    limiter.acquire().await;
+                })
+            })
+            .collect::<Vec<_>>();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Waiting for the permit does not reserve tokens
+        assert!(tokens(&limiter) > -0.5);
+        assert!(waiters.iter().all(|waiter| !waiter.is_finished()));
+
+        drop(permit);
+        for waiter in waiters {
+            waiter.abort();
+        }
+    }
+
+    #[test]
+    fn throttling_halves_concurrency() {
+        let limiter = Limiter::new(8, None);
+        limiter.record_throttled();
+        assert_eq!(limiter.concurrency(), 4);
+        // Requests that were in flight are throttled too, they don't shrink the limit again
+        limiter.record_throttled();
+        assert_eq!(limiter.concurrency(), 4);
+        assert_eq!(limiter.semaphore.available_permits(), 4);
+    }
+
+    #[test]
+    fn successes_grow_concurrency() {
+        let limiter = Limiter::new(8, None);
+        limiter.record_throttled();
+        for _ in 0..4 {
+            limiter.record_success();
+        }
+        assert_eq!(limiter.concurrency(), 5);
+        assert_eq!(limiter.semaphore.available_permits(), 5);
+    }
+}
@@ -0,0 +1,119 @@
+pub mod cache;
+pub mod limiter;
+pub mod retry;
+pub mod transport;
+
+use std::{fmt, time::Duration};
+
+use bytes::Bytes;
+use reqwest::{header::HeaderMap, Client, Request, StatusCode, Url};
+use serde::de::DeserializeOwned;
+
+use self::transport::Transport;
+use crate::{
+    error::{OrlyError, Result},
+    redact::RedactedHeaders,
+};
+
+/// A fully buffered http response.
+///
+/// The body is read as part of the request so that a connection dropped mid-transfer is
+/// retried the same way as a failed status.
+pub struct Response {
+    url: Url,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Response {
+    pub(crate) fn new(url: Url, status: StatusCode, headers: HeaderMap, body: Bytes) -> Self {
+        Self {
+            url,
+            status,
+            headers,
+            body,
+        }
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+
+    pub fn bytes(self) -> Bytes {
+        self.body
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("url", &self.url.as_str())
+            .field("status", &self.status)
+            .field("headers", &RedactedHeaders(&self.headers))
+            .field("body_len", &self.body.len())
+            .finish()
+    }
+}
+
+/// Reason a single request attempt failed
+pub(crate) struct Failure {
+    pub error: OrlyError,
+    /// Delay requested by the server
+    pub retry_after: Option<Duration>,
+}
+
+impl From<reqwest::Error> for Failure {
+    fn from(error: reqwest::Error) -> Self {
+        Self {
+            error: error.into(),
+            retry_after: None,
+        }
+    }
+}
+
+/// Make a single attempt to send the request through the transport and read the response.
+/// Responses of `private` requests are never recorded.
+pub(crate) async fn send(
+    client: &Client,
+    transport: &Transport,
+    request: Request,
+    private: bool,
+) -> std::result::Result<Response, Failure> {
+    let response = transport.execute(client, request, private).await?;
+    let status = response.status();
+
+    if status.is_client_error() || status.is_server_error() {
+        let retry_after = retry::retry_after(status, response.headers());
+        return Err(Failure {
+            error: OrlyError::from_status(status, response.url(), retry_after),
+            retry_after,
+        });
+    }
+
+    let url = response.url().clone();
+    let headers = response.headers().clone();
+    let body = response.bytes().await?;
+
+    Ok(Response::new(url, status, headers, body))
+}
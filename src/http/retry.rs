@@ -0,0 +1,160 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    Method, StatusCode,
+};
+
+/// Controls how failed requests are retried.
+///
+/// Only idempotent requests (`GET`, `HEAD`) are ever retried. The delay between attempts grows
+/// exponentially from `base_delay` up to `max_delay` with full jitter, unless the server asks
+/// for a specific delay with a `Retry-After` header.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt
+    pub retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32) -> Self {
+        Self {
+            retries,
+            ..Default::default()
+        }
+    }
+
+    /// Policy that never retries
+    pub fn none() -> Self {
+        Self::new(0)
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.retries + 1
+    }
+
+    pub(crate) fn should_retry(&self, method: &Method, attempt: u32) -> bool {
+        attempt < self.max_attempts() && (method == Method::GET || method == Method::HEAD)
+    }
+
+    /// Delay before the next attempt. `attempt` is the 1-based number of the attempt that failed.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        // Full jitter, spreads out retries of requests that failed at the same time
+        exp.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+
+    /// Delay before the next attempt, the one requested by the server if any. A server asking
+    /// for more than `max_delay` is not waited for longer than that.
+    pub(crate) fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        match retry_after {
+            Some(retry_after) => retry_after.min(self.max_delay),
+            None => self.backoff(attempt),
+        }
+    }
+}
+
+/// Status codes that are worth retrying
+pub(crate) fn is_transient_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Network level errors that are worth retrying
+pub(crate) fn is_transient_error(err: &reqwest::Error) -> bool {
+    err.is_timeout() || err.is_connect() || err.is_request() || err.is_body()
+}
+
+/// Parse `Retry-After` header of 429 and 503 responses. The header can either contain the
+/// number of seconds to wait or an http date.
+pub(crate) fn retry_after(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+        return None;
+    }
+
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        headers
+    }
+
+    #[test]
+    fn retry_after_seconds() {
+        assert_eq!(
+            retry_after(StatusCode::TOO_MANY_REQUESTS, &headers("12")),
+            Some(Duration::from_secs(12))
+        );
+        assert_eq!(retry_after(StatusCode::NOT_FOUND, &headers("12")), None);
+    }
+
+    #[test]
+    fn retry_after_date() {
+        let date = (Utc::now() + chrono::Duration::seconds(120)).to_rfc2822();
+        let delay = retry_after(StatusCode::SERVICE_UNAVAILABLE, &headers(&date)).unwrap();
+        assert!(delay > Duration::from_secs(100) && delay <= Duration::from_secs(120));
+    }
+
+    #[test]
+    fn delay_is_clamped_to_max_delay() {
+        let policy = RetryPolicy::default();
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(5))),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(86400))),
+            policy.max_delay
+        );
+        let date = (Utc::now() + chrono::Duration::days(1)).to_rfc2822();
+        let requested = retry_after(StatusCode::TOO_MANY_REQUESTS, &headers(&date));
+        assert_eq!(policy.delay(1, requested), policy.max_delay);
+    }
+
+    #[test]
+    fn backoff_is_bounded() {
+        let policy = RetryPolicy::default();
+        for attempt in 1..40 {
+            assert!(policy.backoff(attempt) <= policy.max_delay);
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use bytes::Bytes;
+use log::{trace, warn};
+use reqwest::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, CACHE_CONTROL, CONTENT_TYPE, ETAG, LAST_MODIFIED,
+        RETRY_AFTER,
+    },
+    Client, Method, Request, ResponseBuilderExt, StatusCode, Url,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+
+use super::cache::write_atomic;
+use crate::{
+    error::{OrlyError, Result},
+    redact::{redact, Redacted},
+};
+
+/// Response headers that are recorded, everything else, e.g. cookies, is dropped. The body is
+/// stored decompressed, so content encoding and length are left out too.
+const RECORDED_HEADERS: &[HeaderName] = &[
+    CONTENT_TYPE,
+    ETAG,
+    LAST_MODIFIED,
+    CACHE_CONTROL,
+    RETRY_AFTER,
+];
+
+/// Where responses come from.
+///
+/// A recorded run can be replayed later without network access, e.g. to reproduce a broken
+/// book from a bug report. Every exchange is stored as two files named after the sha256 of the
+/// request method and url: the raw body and a json file with the status and headers.
+/// Sign in and account requests are not recorded, only the response headers needed to replay
+/// the response are stored, urls are redacted and request headers and bodies are never
+/// stored, so the fixture directory contains no credentials.
+#[derive(Debug, Clone, Default)]
+pub enum Transport {
+    /// Send requests over the network
+    #[default]
+    Network,
+    /// Send requests over the network and save every response to a fixture directory
+    Record(PathBuf),
+    /// Answer requests with the responses saved by [`Transport::Record`]
+    Replay(PathBuf),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Exchange {
+    method: String,
+    /// Redacted request url, the file names are derived from the original one
+    url: String,
+    /// Final url after redirects, redacted
+    response_url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+impl Transport {
+    pub fn record<P: Into<PathBuf>>(dir: P) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create fixture directory {:?}", dir))?;
+        Ok(Self::Record(dir))
+    }
+
+    pub fn replay<P: Into<PathBuf>>(dir: P) -> Result<Self> {
+        let dir = dir.into();
+        if !dir.is_dir() {
+            return Err(OrlyError::Other(anyhow::anyhow!(
+                "fixture directory {:?} does not exist",
+                dir
+            )));
+        }
+        Ok(Self::Replay(dir))
+    }
+
+    /// Send the request, or look up its recorded response when replaying. Recorded responses
+    /// that are missing are answered with 404. Responses of `private` requests, e.g. the ones
+    /// containing tokens or account details, are sent but not recorded.
+    pub(crate) async fn execute(
+        &self,
+        client: &Client,
+        request: Request,
+        private: bool,
+    ) -> reqwest::Result<reqwest::Response> {
+        match self {
+            Transport::Network => client.execute(request).await,
+            Transport::Record(_) if private => {
+                trace!("Not recording private request {}", Redacted(request.url()));
+                client.execute(request).await
+            }
+            Transport::Record(dir) => record(dir, client, request).await,
+            Transport::Replay(dir) => Ok(replay(dir, &request).await),
+        }
+    }
+}
+
+async fn record(
+    dir: &Path,
+    client: &Client,
+    request: Request,
+) -> reqwest::Result<reqwest::Response> {
+    let method = request.method().clone();
+    let request_url = request.url().clone();
+
+    let response = client.execute(request).await?;
+    let url = response.url().clone();
+    let status = response.status();
+    let headers = response
+        .headers()
+        .iter()
+        .filter(|(name, _)| RECORDED_HEADERS.contains(name))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect::<HeaderMap>();
+    let body = response.bytes().await?;
+
+    let exchange = Exchange {
+        method: method.to_string(),
+        url: redact(request_url.as_str()).into_owned(),
+        response_url: redact(url.as_str()).into_owned(),
+        status: status.as_u16(),
+        headers: headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+    };
+    let (body_path, exchange_path) = exchange_paths(dir, &method, &request_url);
+    if let Err(err) = save(&body_path, &exchange_path, &exchange, &body).await {
+        warn!(
+            "Failed to record {}: {}",
+            Redacted(&request_url),
+            Redacted(&err)
+        );
+    }
+
+    Ok(build_response(url, status, headers, body))
+}
+
+async fn replay(dir: &Path, request: &Request) -> reqwest::Response {
+    let (body_path, exchange_path) = exchange_paths(dir, request.method(), request.url());
+    match load(&body_path, &exchange_path).await {
+        Some(response) => response,
+        None => {
+            warn!(
+                "No recorded response for {} {}, replying with 404",
+                request.method(),
+                Redacted(request.url())
+            );
+            build_response(
+                request.url().clone(),
+                StatusCode::NOT_FOUND,
+                HeaderMap::new(),
+                Bytes::new(),
+            )
+        }
+    }
+}
+
+fn exchange_paths(dir: &Path, method: &Method, url: &Url) -> (PathBuf, PathBuf) {
+    let key = format!(
+        "{:x}",
+        Sha256::digest(format!("{} {}", method, url).as_bytes())
+    );
+    (
+        dir.join(format!("{}.body", key)),
+        dir.join(format!("{}.json", key)),
+    )
+}
+
+async fn save(
+    body_path: &Path,
+    exchange_path: &Path,
+    exchange: &Exchange,
+    body: &[u8],
+) -> Result<()> {
+    write_atomic(body_path, body).await?;
+    write_atomic(exchange_path, &serde_json::to_vec_pretty(exchange)?).await?;
+    Ok(())
+}
+
+async fn load(body_path: &Path, exchange_path: &Path) -> Option<reqwest::Response> {
+    let exchange = fs::read(exchange_path).await.ok()?;
+    let exchange = match serde_json::from_slice::<Exchange>(&exchange) {
+        Ok(exchange) => exchange,
+        Err(err) => {
+            warn!("Ignoring invalid recording {:?}: {}", exchange_path, err);
+            return None;
+        }
+    };
+    let body = fs::read(body_path).await.ok()?;
+    trace!("Replaying {} {}", exchange.method, Redacted(&exchange.url));
+
+    let mut headers = HeaderMap::new();
+    for (name, value) in exchange.headers {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            headers.append(name, value);
+        }
+    }
+
+    Some(build_response(
+        exchange.response_url.parse().ok()?,
+        StatusCode::from_u16(exchange.status).ok()?,
+        headers,
+        body.into(),
+    ))
+}
+
+fn build_response(
+    url: Url,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+) -> reqwest::Response {
+    let mut response = ::http::Response::builder()
+        .url(url)
+        .status(status)
+        .body(body)
+        .expect("valid response parts");
+    *response.headers_mut() = headers;
+    response.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Answer every connection with a json body and a session cookie
+    async fn server() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).await;
+                let body = r#"{"logged_in": true}"#;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Set-Cookie: orm-jwt=secret\r\nServer: mock\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        url.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let base = server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let client = Client::new();
+        let recorder = Transport::record(dir.path()).unwrap();
+
+        let book = base.join("api/v1/book/1234/?token=SECRET").unwrap();
+        let login = base.join("api/v1/auth/login/").unwrap();
+        for (url, private) in [(&book, false), (&login, true)] {
+            let request = client.get(url.clone()).build().unwrap();
+            let response = recorder.execute(&client, request, private).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        // Only the book is recorded, with the content type but without the cookie and the token
+        let files = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(files, 2);
+        let (_, exchange_path) = exchange_paths(dir.path(), &Method::GET, &book);
+        let exchange = std::fs::read_to_string(exchange_path).unwrap();
+        let exchange = serde_json::from_str::<Exchange>(&exchange).unwrap();
+        let names = exchange.headers.iter().map(|(name, _)| name.as_str());
+        assert_eq!(names.collect::<Vec<_>>(), ["content-type"]);
+        assert!(!exchange.url.contains("SECRET"));
+        assert!(!exchange.response_url.contains("SECRET"));
+
+        let replayer = Transport::replay(dir.path()).unwrap();
+        let replay = |url: &Url| {
+            let request = client.get(url.clone()).build().unwrap();
+            replayer.execute(&client, request, false)
+        };
+        let response = replay(&book).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), r#"{"logged_in": true}"#);
+        assert_eq!(
+            replay(&login).await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -1,5 +1,14 @@
+pub mod book_ids;
 pub mod client;
+pub mod config;
+pub mod cookies;
+pub mod credentials;
+pub mod endpoints;
 pub mod epub;
 pub mod error;
+pub mod http;
 pub mod models;
+pub mod naming;
+pub mod progress;
+pub mod redact;
 pub mod templates;
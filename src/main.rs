@@ -1,21 +1,51 @@
-use clap::{ArgAction, Parser, ValueHint};
+use clap::{
+    builder::RangedU64ValueParser, error::ErrorKind, parser::ValueSource, Arg, ArgAction,
+    CommandFactory, FromArgMatches, Parser, ValueEnum, ValueHint,
+};
 use fern::colors::{Color, ColoredLevelConfig};
-use log::{error, info};
+use futures::stream::{self, StreamExt};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use log::{error, info, warn};
 use orly::{
-    client::{Authenticated, OreillyClient},
-    epub::builder::EpubBuilder,
-    error::Result,
-    models::Book,
+    book_ids::{dedup_book_ids, parse_book_id, read_book_ids},
+    client::{Authenticated, OreillyClient, Unauthenticated},
+    config::Config,
+    cookies::{self, BrowserProfile},
+    credentials::{default_netrc_path, netrc_entry, prompt_password, read_password_file},
+    endpoints::Endpoints,
+    epub::{
+        builder::{EpubBuilder, EpubVersion, ImageStats},
+        check::{check, CheckReport},
+        fingerprint::{fingerprint, StoredMetadata},
+        images::{ImagePolicies, ImagePolicyRule},
+    },
+    error::{OrlyError, Result},
+    http::{cache::Cache, retry::RetryPolicy, transport::Transport},
+    naming::{available_path, PathTemplate},
+    progress::{ProgressCallback, ProgressEvent},
+    redact::redact,
 };
-use sanitize_filename::sanitize;
+use reqwest::Url;
+use serde::Serialize;
 use std::{
-    io::Cursor,
+    collections::HashMap,
+    ffi::OsString,
+    io::{BufWriter, Cursor, IsTerminal},
     path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use tokio::fs::write;
+use tokio_util::sync::CancellationToken;
 
 use anyhow::Context;
 
+tokio::task_local! {
+    /// Book being downloaded by the current task, used to prefix log lines
+    static BOOK_ID: String;
+}
+
 fn path_exists(v: &str) -> std::result::Result<PathBuf, String> {
     let path_buf: PathBuf = PathBuf::from(v);
     if path_buf.as_path().exists() {
@@ -24,28 +54,119 @@ fn path_exists(v: &str) -> std::result::Result<PathBuf, String> {
     Err(format!("The specified path does not exist: {}", v))
 }
 
+/// What to do when the epub of a book already exists
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum IfExists {
+    /// Keep the existing epub
+    Skip,
+    /// Replace the existing epub
+    Overwrite,
+    /// Save next to the existing epub with a number appended to the name
+    Rename,
+    /// Replace the existing epub only if the book changed since it was saved
+    Update,
+}
+
 #[derive(Parser, Debug)]
 #[clap(author, about, version)]
 struct CliArgs {
+    #[clap(
+        long,
+        help = "Toml file with defaults for the options [default: user config directory]",
+        value_hint = ValueHint::FilePath,
+        value_parser = path_exists
+    )]
+    config: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Use the options of a [profile.NAME] table of the config file"
+    )]
+    profile: Option<String>,
     #[clap(
         short,
         long,
         value_names = &["EMAIL", "PASSWORD"],
-        help = "Sign in credentials",
-        required_unless_present = "cookie",
+        help = "Sign in credentials, prefer --email as the password shows up in the shell history",
         conflicts_with = "cookie",
         number_of_values = 2
     )]
     creds: Option<Vec<String>>,
     #[clap(
         long,
-        value_name = "COOKIE_STRING",
-        help = "Cookie string",
-        required_unless_present = "creds"
+        help = "Email to sign in with [env: ORLY_EMAIL]. The password is read from --password-file, ORLY_PASSWORD or the netrc file, or asked for",
+        conflicts_with_all = ["cookie", "creds"]
+    )]
+    email: Option<String>,
+    #[clap(
+        long,
+        help = "File with the sign in password on the first line",
+        value_hint = ValueHint::FilePath,
+        value_parser = path_exists,
+        conflicts_with_all = ["cookie", "creds"]
     )]
+    password_file: Option<PathBuf>,
+    #[clap(long, value_name = "COOKIE_STRING", help = "Cookie string")]
     cookie: Option<String>,
+    #[clap(
+        long,
+        help = "Netscape cookies.txt or json cookie export to sign in with",
+        value_hint = ValueHint::FilePath,
+        value_parser = path_exists,
+        conflicts_with_all = ["cookie", "creds", "email"]
+    )]
+    cookie_file: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "BROWSER[:PROFILE]",
+        help = "Sign in with cookies of a local browser profile: firefox, chrome, chromium, brave or edge",
+        conflicts_with_all = ["cookie", "cookie_file", "creds", "email"]
+    )]
+    cookies_from_browser: Option<BrowserProfile>,
+    #[clap(
+        long,
+        help = "File to save the session to and restore it from [default: user data directory]",
+        value_hint = ValueHint::FilePath,
+        conflicts_with = "no_session"
+    )]
+    session_file: Option<PathBuf>,
+    #[clap(long, help = "Do not save or restore the session")]
+    no_session: bool,
+    #[clap(
+        long,
+        help = "Toml file with custom O'Reilly hosts",
+        value_hint = ValueHint::FilePath,
+        value_parser = path_exists
+    )]
+    endpoints: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "URL",
+        help = "Base url of the learning platform [default: https://learning.oreilly.com/]"
+    )]
+    learning_url: Option<Url>,
+    #[clap(
+        long,
+        value_name = "URL",
+        help = "Base url of the authentication api [default: https://api.oreilly.com/]"
+    )]
+    auth_url: Option<Url>,
     #[clap(short, long, help = "Apply CSS tweaks for kindle devices")]
     kindle: bool,
+    #[clap(
+        long,
+        value_name = "FORMAT=POLICY",
+        help = "How large images of a format are optimized: auto converts them to jpeg, or png if transparent, same-format keeps the format, keep leaves them as is, e.g. png=same-format"
+    )]
+    image_policy: Vec<ImagePolicyRule>,
+    #[clap(
+        long,
+        value_name = "VERSION",
+        help = "EPUB version of the saved book, 2 or 3. EPUB 3 books keep the EPUB 2 table of contents for older readers",
+        default_value = "2"
+    )]
+    epub_version: EpubVersion,
+    #[clap(long, help = "Fail books that do not pass the epub conformance check")]
+    strict: bool,
     #[clap(short, long, help = "Level of verbosity", action = ArgAction::Count)]
     verbose: u8,
     #[clap(
@@ -55,8 +176,39 @@ struct CliArgs {
         default_value = "20"
     )]
     threads: usize,
-    #[clap(help = "Book ID to download. Digits from the URL", required = true)]
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Number of books to download at the same time, sharing the request limits",
+        default_value = "1",
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    parallel_books: usize,
+    #[clap(
+        long,
+        help = "Number of times a failed request is retried",
+        default_value = "3"
+    )]
+    retries: u32,
+    #[clap(
+        long,
+        value_name = "REQUESTS",
+        help = "Maximum number of http requests per second"
+    )]
+    rate_limit: Option<f64>,
+    #[clap(
+        help = "Book ID, ISBN or URL of the book to download",
+        required_unless_present = "input"
+    )]
     book_ids: Vec<String>,
+    #[clap(
+        short,
+        long,
+        help = "Text or csv file with one book ID, ISBN or URL per line",
+        value_hint = ValueHint::FilePath,
+        value_parser = path_exists
+    )]
+    input: Option<PathBuf>,
     #[clap(
         short,
         long,
@@ -67,31 +219,243 @@ struct CliArgs {
         value_parser = path_exists,
     )]
     output: PathBuf,
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        help = "Name of the saved epub, placeholders: {title}, {subtitle}, {authors}, {first_author}, {year}, {issued}, {isbn}, {id}, {publisher}, {language}",
+        default_value = "{title} ({issued}) - {authors}"
+    )]
+    filename_template: String,
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        help = "Directories inside the output directory to save the epub to, e.g. {publisher}/{first_author}"
+    )]
+    dir_template: Option<String>,
+    #[clap(
+        long,
+        value_name = "SEPARATOR",
+        help = "Separator of the names in {authors}",
+        default_value = ", "
+    )]
+    authors_separator: String,
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Maximum number of names in {authors}, the rest are replaced with \"et al.\""
+    )]
+    max_authors: Option<usize>,
+    #[clap(
+        long,
+        value_enum,
+        help = "What to do when the epub already exists",
+        default_value = "overwrite"
+    )]
+    if_exists: IfExists,
+    #[clap(
+        long,
+        help = "Directory to cache downloaded files in [default: user cache directory]",
+        value_hint = ValueHint::DirPath,
+        conflicts_with = "no_cache"
+    )]
+    cache_dir: Option<PathBuf>,
+    #[clap(long, help = "Do not cache downloaded files")]
+    no_cache: bool,
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Save the status of every requested book to a json file",
+        value_hint = ValueHint::FilePath
+    )]
+    report: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "DIR",
+        help = "Save every http response to a directory so that the run can be replayed",
+        value_hint = ValueHint::DirPath,
+        conflicts_with = "replay"
+    )]
+    record: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "DIR",
+        help = "Replay a recorded run without network access",
+        value_hint = ValueHint::DirPath,
+        value_parser = path_exists
+    )]
+    replay: Option<PathBuf>,
 }
 
-fn generate_filename(book: &Book) -> String {
-    let authors = book
-        .authors
-        .iter()
-        .map(|a| a.name.as_str())
-        .collect::<Vec<&str>>()
-        .join("");
+/// Parse the command line, using the options of the config file as defaults. Config options that
+/// conflict with the ones given on the command line are ignored, so that e.g. `--creds` replaces
+/// a cookie from the config.
+fn parse_args() -> CliArgs {
+    parse_args_from(std::env::args_os()).unwrap_or_else(|err| err.exit())
+}
 
-    let filename = if authors.is_empty() {
-        format!("{} ({})", book.title, book.issued)
-    } else {
-        format!("{} ({}) - {}", book.title, book.issued, authors)
+fn parse_args_from<I: IntoIterator<Item = T>, T: Into<OsString> + Clone>(
+    args: I,
+) -> std::result::Result<CliArgs, clap::Error> {
+    let args = args.into_iter().map(Into::into).collect::<Vec<OsString>>();
+    let given = CliArgs::command()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)?;
+    let mut command = CliArgs::command();
+
+    let config_path = given
+        .get_one::<PathBuf>("config")
+        .cloned()
+        .or_else(|| Config::default_path().filter(|path| path.exists()));
+    let profile = given.get_one::<String>("profile");
+    let config = match (&config_path, profile) {
+        (Some(path), profile) => Config::from_file(path).and_then(|config| {
+            let options = config.options(profile.map(String::as_str))?;
+            Ok(Some((config, options)))
+        }),
+        (None, Some(profile)) => Err(OrlyError::InvalidConfig(format!(
+            "profile {} requires a config file",
+            profile
+        ))),
+        (None, None) => Ok(None),
+    };
+    let (config, options) = match config {
+        Ok(Some(config)) => config,
+        Ok(None) => return CliArgs::try_parse_from(&args),
+        Err(err) => return Err(command.error(ErrorKind::InvalidValue, err)),
     };
 
-    sanitize(filename)
+    for (key, value) in options {
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()))
+            .filter(|arg| !["config", "profile"].contains(&arg.get_id().as_str()))
+        else {
+            let err = OrlyError::InvalidConfig(format!("unknown option {}", key));
+            return Err(command.error(ErrorKind::UnknownArgument, err));
+        };
+        let conflicts = |a: &Arg, b: &Arg| {
+            command
+                .get_arg_conflicts_with(a)
+                .iter()
+                .any(|conflict| conflict.get_id() == b.get_id())
+        };
+        let overridden = command.get_arguments().any(|other| {
+            given.value_source(other.get_id().as_str()) == Some(ValueSource::CommandLine)
+                && (other.get_id() == arg.get_id()
+                    || conflicts(arg, other)
+                    || conflicts(other, arg))
+        });
+        if overridden {
+            continue;
+        }
+
+        let id = arg.get_id().clone();
+        let values = match config.values(&key, &value) {
+            Ok(values) => values,
+            Err(err) => return Err(command.error(ErrorKind::InvalidValue, err)),
+        };
+        command = command.mut_arg(id, |arg| arg.default_values(values));
+    }
+
+    let matches = command.try_get_matches_from_mut(&args)?;
+    CliArgs::from_arg_matches(&matches).map_err(|err| err.format(&mut command))
+}
+
+/// Progress bar of the whole batch and one bar per book being downloaded
+struct ProgressBars {
+    multi: MultiProgress,
+    batch: ProgressBar,
+    books: Mutex<HashMap<String, ProgressBar>>,
+}
+
+impl ProgressBars {
+    fn new(multi: MultiProgress, books: usize) -> Arc<Self> {
+        let batch = multi.add(ProgressBar::new(books as u64));
+        batch.set_style(
+            ProgressStyle::with_template("Books [{bar:40}] {pos}/{len} {elapsed_precise}")
+                .expect("valid template")
+                .progress_chars("=> "),
+        );
+        Arc::new(Self {
+            multi,
+            batch,
+            books: Default::default(),
+        })
+    }
+
+    fn start_book(&self, book_id: &str) -> ProgressBar {
+        let bar = self.multi.insert_before(&self.batch, ProgressBar::new(0));
+        bar.set_style(
+            ProgressStyle::with_template("{prefix} [{bar:40}] {pos}/{len} {msg}")
+                .expect("valid template")
+                .progress_chars("=> "),
+        );
+        bar.set_prefix(book_id.to_string());
+        self.books
+            .lock()
+            .unwrap()
+            .insert(book_id.to_string(), bar.clone());
+        bar
+    }
+
+    fn finish_book(&self, book_id: &str) {
+        if let Some(bar) = self.books.lock().unwrap().remove(book_id) {
+            bar.finish_and_clear();
+        }
+        self.batch.inc(1);
+    }
+
+    fn finish(&self) {
+        self.batch.finish();
+    }
+
+    /// Callback of the client, events are routed to the bar of their book
+    fn callback(self: &Arc<Self>) -> ProgressCallback {
+        let bars = self.clone();
+        Arc::new(move |event| {
+            if let Some(bar) = bars.books.lock().unwrap().get(&event.book_id) {
+                update_bar(bar, event);
+            }
+        })
+    }
+}
+
+fn update_bar(bar: &ProgressBar, event: &ProgressEvent) {
+    bar.set_length(event.total as u64);
+    bar.set_position(event.done as u64);
+    bar.set_message(format!("{} ({})", event.phase, HumanBytes(event.bytes)));
+}
+
+/// Result of a successfully downloaded book, or of an existing epub that was kept
+struct Downloaded {
+    status: BookStatus,
+    title: String,
+    output: PathBuf,
+    size: u64,
+    images: Option<ImageStats>,
+    conformance: CheckReport,
+}
+
+impl Downloaded {
+    fn kept(title: &str, output: PathBuf) -> Self {
+        Self {
+            status: BookStatus::Kept,
+            title: title.to_string(),
+            size: std::fs::metadata(&output).map_or(0, |meta| meta.len()),
+            output,
+            images: None,
+            conformance: Default::default(),
+        }
+    }
 }
 
 async fn run(
     client: &OreillyClient<Authenticated>,
     book_id: &str,
-    output: &Path,
-    kindle: bool,
-) -> Result<()> {
+    cli_args: &CliArgs,
+    path_template: &PathTemplate,
+    bar: ProgressBar,
+) -> Result<Downloaded> {
     info!("==== Getting book info =====");
     let book = client.fetch_book_details(book_id).await?;
     info!("Title: {:?}", book.title);
@@ -104,32 +468,158 @@ async fn run(
             .join(", ")
     );
 
-    let chapters = client.fetch_book_chapters(book_id).await?;
+    let if_exists = cli_args.if_exists;
+    let mut output = cli_args.output.join(path_template.render(
+        &book,
+        book_id,
+        "epub",
+        cli_args.output.as_os_str().len(),
+    ));
+    if output.exists() {
+        match if_exists {
+            IfExists::Skip => {
+                info!("{:?} already exists, skipping", output);
+                return Ok(Downloaded::kept(&book.title, output));
+            }
+            IfExists::Rename => {
+                output = available_path(&output);
+                info!("Epub already exists, saving as {:?}", output);
+            }
+            IfExists::Overwrite | IfExists::Update => {}
+        }
+    }
+
+    let chapters_meta = client.fetch_book_chapters_meta(book_id).await?;
+    // Chapters are revalidated with the server, unchanged ones are not downloaded again
+    let chapters = client
+        .fetch_book_chapters_content(book_id, chapters_meta)
+        .await?;
 
     info!("Downloaded {} chapters", chapters.len());
 
-    let output = output.join(generate_filename(&book)).with_extension("epub");
+    let image_policies = cli_args
+        .image_policy
+        .iter()
+        .copied()
+        .collect::<ImagePolicies>();
+    if if_exists == IfExists::Update && output.exists() {
+        let current = fingerprint(
+            &book,
+            &chapters,
+            cli_args.kindle,
+            cli_args.epub_version,
+            &image_policies,
+        );
+        match StoredMetadata::read(&output) {
+            Ok(stored)
+                if stored.book_id.as_deref() == Some(book.identifier.as_str())
+                    && stored.fingerprint.as_deref() == Some(current.as_str()) =>
+            {
+                info!("{:?} is up to date, skipping", output);
+                return Ok(Downloaded::kept(&book.title, output));
+            }
+            Ok(_) => info!("Book changed since {:?} was saved, updating", output),
+            Err(err) => warn!("Failed to read the existing epub, replacing it: {}", err),
+        }
+    }
 
     let toc = client.fetch_toc(book_id).await?;
     info!("Toc size: {}", toc.len());
 
     let mut buffer = Cursor::new(Vec::new());
 
-    EpubBuilder::new(&book, kindle)?
+    let mut builder = EpubBuilder::new(&book, cli_args.kindle, client.endpoints())?;
+    builder
+        .with_progress(Arc::new(move |event| update_bar(&bar, event)))
+        .with_version(cli_args.epub_version)
+        .with_image_policies(image_policies)
         .chapters(&chapters)?
         .toc(&toc)?
         .generate(&mut buffer, client)
         .await?;
 
-    write(&output, buffer.get_ref())
-        .await
-        .context("Failed to write data to file")?;
+    let conformance = check(buffer.get_ref())?;
+    for issue in conformance.warnings() {
+        warn!("Conformance warning: {}", issue);
+    }
+    for issue in conformance.errors() {
+        error!("Conformance error: {}", issue);
+    }
+    if cli_args.strict && conformance.has_errors() {
+        return Err(OrlyError::InvalidEpub(format!(
+            "{} conformance errors, the first one: {}",
+            conformance.errors().count(),
+            conformance.errors().next().unwrap()
+        )));
+    }
+
+    save_epub(&output, buffer.get_ref()).await?;
     info!("Done! Saved as {:?}", output);
 
+    Ok(Downloaded {
+        status: BookStatus::Downloaded,
+        title: book.title.clone(),
+        output,
+        size: buffer.get_ref().len() as u64,
+        images: Some(builder.image_stats()),
+        conformance,
+    })
+}
+
+/// Write next to the destination first so that an interrupted run never leaves a truncated
+/// epub behind. Directories of the templates are only created once there is an epub to save.
+async fn save_epub(output: &Path, epub: &[u8]) -> Result<()> {
+    let parent = output.parent().unwrap_or(Path::new("."));
+    tokio::fs::create_dir_all(parent)
+        .await
+        .with_context(|| format!("Failed to create directory {:?}", parent))?;
+
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".orly-").suffix(".epub.part");
+    // Temporary files are private by default, the epub gets the usual permissions instead
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        builder.permissions(std::fs::Permissions::from_mode(0o666));
+    }
+    let tmp = builder
+        .tempfile_in(parent)
+        .context("Failed to create temporary file")?;
+    write(tmp.path(), epub)
+        .await
+        .context("Failed to write data to file")?;
+    tmp.persist(output)
+        .context("Failed to move the epub to the output directory")?;
     Ok(())
 }
 
-fn set_up_logging(verbosity: u8) {
+fn save_session(client: &OreillyClient<Authenticated>, session_file: Option<&Path>) {
+    if let Some(session_file) = session_file {
+        if let Err(err) = client.save_session(session_file) {
+            warn!("Failed to save session: {}", err);
+        }
+    }
+}
+
+/// Token cancelled on the first Ctrl-C, the second one exits immediately
+fn cancel_on_ctrl_c() -> CancellationToken {
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        warn!("Stopping, press Ctrl-C again to exit immediately");
+        cancel.cancel();
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+    token
+}
+
+/// Log lines are printed above the progress bars
+fn set_up_logging(verbosity: u8, progress: MultiProgress) {
     let mut base_config = fern::Dispatch::new();
 
     base_config = match verbosity {
@@ -150,41 +640,556 @@ fn set_up_logging(verbosity: u8) {
 
     base_config
         .format(move |out, message, record| {
+            let book = BOOK_ID
+                .try_with(|book_id| format!("[{}] ", book_id))
+                .unwrap_or_default();
             out.finish(format_args!(
-                "{color_line}[{date}][{level}{color_line}] {message}\x1B[0m",
+                "{color_line}[{date}][{level}{color_line}] {book}{message}\x1B[0m",
                 color_line = format_args!(
                     "\x1B[{}m",
                     colors_line.get_color(&record.level()).to_fg_str()
                 ),
                 date = chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
                 level = colors_level.color(record.level()),
-                message = message,
+                book = book,
+                message = redact(&message.to_string()),
             ));
         })
-        .chain(std::io::stdout())
+        .chain(fern::Output::call(move |record| {
+            progress.suspend(|| println!("{}", record.args()))
+        }))
         .apply()
         .expect("failed to initialize logging.");
 }
 
+/// Exit codes besides 0 for success and 2 for invalid arguments
+mod exit_code {
+    /// Unexpected error before any book was downloaded, or every book failed
+    pub const FAILURE: u8 = 1;
+    /// Signing in failed or the session expired
+    pub const AUTHENTICATION: u8 = 3;
+    /// Some of the books failed, but not all of them
+    pub const PARTIAL_FAILURE: u8 = 4;
+    pub const CANCELLED: u8 = 130;
+}
+
+fn exit_code(err: &OrlyError) -> ExitCode {
+    ExitCode::from(match err {
+        OrlyError::Cancelled => exit_code::CANCELLED,
+        err if err.is_authentication() => exit_code::AUTHENTICATION,
+        _ => exit_code::FAILURE,
+    })
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum BookStatus {
+    Downloaded,
+    /// The epub already existed and was kept because of `--if-exists`
+    Kept,
+    Failed,
+    /// Not attempted because of an earlier authentication failure
+    Skipped,
+    /// Interrupted or not attempted because of Ctrl-C
+    Cancelled,
+}
+
+/// Entry of the `--report` file
+#[derive(Serialize, Debug)]
+struct BookReport {
+    id: String,
+    status: BookStatus,
+    title: Option<String>,
+    output: Option<PathBuf>,
+    size: Option<u64>,
+    duration_secs: f64,
+    images_original_size: Option<u64>,
+    images_optimized_size: Option<u64>,
+    conformance_warnings: Vec<String>,
+    conformance_errors: Vec<String>,
+    error: Option<String>,
+}
+
+impl BookReport {
+    fn new(id: &str, status: BookStatus, duration: Duration) -> Self {
+        Self {
+            id: id.to_string(),
+            status,
+            title: None,
+            output: None,
+            size: None,
+            duration_secs: duration.as_secs_f64(),
+            images_original_size: None,
+            images_optimized_size: None,
+            conformance_warnings: Vec::new(),
+            conformance_errors: Vec::new(),
+            error: None,
+        }
+    }
+
+    fn downloaded(id: &str, downloaded: Downloaded, duration: Duration) -> Self {
+        Self {
+            title: Some(downloaded.title),
+            output: Some(downloaded.output),
+            size: Some(downloaded.size),
+            images_original_size: downloaded.images.map(|images| images.original_bytes),
+            images_optimized_size: downloaded.images.map(|images| images.optimized_bytes),
+            conformance_warnings: downloaded
+                .conformance
+                .warnings()
+                .map(ToString::to_string)
+                .collect(),
+            conformance_errors: downloaded
+                .conformance
+                .errors()
+                .map(ToString::to_string)
+                .collect(),
+            ..Self::new(id, downloaded.status, duration)
+        }
+    }
+
+    fn failed(id: &str, error: &OrlyError, duration: Duration) -> Self {
+        let status = match error {
+            OrlyError::Cancelled => BookStatus::Cancelled,
+            _ => BookStatus::Failed,
+        };
+        Self {
+            error: Some(error.to_string()),
+            ..Self::new(id, status, duration)
+        }
+    }
+
+    /// Not attempted because of `error`
+    fn skipped(id: &str, error: &OrlyError) -> Self {
+        let status = match error {
+            OrlyError::Cancelled => BookStatus::Cancelled,
+            _ => BookStatus::Skipped,
+        };
+        Self {
+            error: Some(error.to_string()),
+            ..Self::new(id, status, Duration::ZERO)
+        }
+    }
+}
+
+/// Exit code when `failed` of `total` books weren't downloaded
+fn failure_code(failed: usize, total: usize) -> u8 {
+    if failed == total {
+        exit_code::FAILURE
+    } else {
+        exit_code::PARTIAL_FAILURE
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Report<'a> {
+    books: &'a [BookReport],
+}
+
+fn write_report(path: Option<&Path>, books: &[BookReport]) {
+    let Some(path) = path else {
+        return;
+    };
+    let result = std::fs::File::create(path)
+        .context("failed to create report file")
+        .and_then(|file| {
+            serde_json::to_writer_pretty(BufWriter::new(file), &Report { books })
+                .context("failed to write report")
+        });
+    match result {
+        Ok(()) => info!("Report saved to {:?}", path),
+        Err(err) => error!("Failed to save report to {:?}: {}", path, err),
+    }
+}
+
 #[tokio::main]
-async fn main() -> Result<()> {
-    let cli_args = CliArgs::parse();
-    set_up_logging(cli_args.verbose);
+async fn main() -> ExitCode {
+    let cli_args = parse_args();
+    let multi_progress = MultiProgress::new();
+    set_up_logging(cli_args.verbose, multi_progress.clone());
 
-    let client = OreillyClient::new(cli_args.threads);
-    let client = if let Some(creds) = &cli_args.creds {
-        client.cred_auth(&creds[0], &creds[1]).await?
+    match download_books(&cli_args, multi_progress).await {
+        Ok(code) => code,
+        Err(err) => {
+            error!("{}", err);
+            exit_code(&err)
+        }
+    }
+}
+
+async fn download_books(cli_args: &CliArgs, multi_progress: MultiProgress) -> Result<ExitCode> {
+    let mut book_ids = cli_args
+        .book_ids
+        .iter()
+        .map(|id| parse_book_id(id))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(input) = &cli_args.input {
+        book_ids.extend(read_book_ids(input)?);
+    }
+    let book_ids = dedup_book_ids(book_ids);
+
+    let mut path_template = PathTemplate::default()
+        .with_filename(&cli_args.filename_template)?
+        .with_authors_separator(&cli_args.authors_separator);
+    if let Some(dir_template) = &cli_args.dir_template {
+        path_template = path_template.with_dir(dir_template)?;
+    }
+    if let Some(max_authors) = cli_args.max_authors {
+        path_template = path_template.with_max_authors(max_authors);
+    }
+
+    let progress = ProgressBars::new(multi_progress, book_ids.len());
+
+    let mut endpoints = match &cli_args.endpoints {
+        Some(path) => Endpoints::from_file(path)?,
+        None => Endpoints::default(),
+    };
+    if let Some(learning_url) = cli_args.learning_url.clone() {
+        endpoints = endpoints.with_learning_url(learning_url);
+    }
+    if let Some(auth_url) = cli_args.auth_url.clone() {
+        endpoints = endpoints.with_auth_url(auth_url);
+    }
+
+    let mut client = OreillyClient::new(cli_args.threads)
+        .with_endpoints(endpoints)
+        .with_retry_policy(RetryPolicy::new(cli_args.retries))
+        .with_progress(progress.callback())
+        .with_cancellation(cancel_on_ctrl_c());
+    if let Some(rate_limit) = cli_args.rate_limit {
+        client = client.with_rate_limit(rate_limit);
+    }
+    let transport = match (&cli_args.record, &cli_args.replay) {
+        (Some(dir), _) => Transport::record(dir)?,
+        (_, Some(dir)) => Transport::replay(dir)?,
+        _ => Transport::Network,
+    };
+    // Cache hits would be missing from the recording and hide the replayed responses
+    let uses_fixtures = !matches!(transport, Transport::Network);
+    client = client.with_transport(transport);
+    if uses_fixtures {
+        info!("Caching is disabled while recording or replaying");
+    } else if !cli_args.no_cache {
+        match cli_args.cache_dir.clone().or_else(Cache::default_dir) {
+            Some(dir) => client = client.with_cache(Cache::new(dir)?),
+            None => warn!("Unable to determine cache directory, caching is disabled"),
+        }
+    }
+    // A replayed session must not replace the real one
+    let session_file = if cli_args.no_session || cli_args.replay.is_some() {
+        None
     } else {
+        cli_args
+            .session_file
+            .clone()
+            .or_else(cookies::default_session_path)
+    };
+
+    let client = match authenticate(client, cli_args, session_file.as_deref()).await {
+        Ok(client) => client,
+        Err(err) => {
+            let reports = book_ids
+                .iter()
+                .map(|id| BookReport::skipped(id, &err))
+                .collect::<Vec<_>>();
+            write_report(cli_args.report.as_deref(), &reports);
+            return Err(err);
+        }
+    };
+    save_session(&client, session_file.as_deref());
+
+    // Set by the first book that failed in a way every other book would fail too
+    let fatal = Mutex::new(None);
+    let mut reports = stream::iter(book_ids.iter().enumerate())
+        .map(|(index, book_id)| {
+            let (client, progress, fatal, path_template) =
+                (&client, &progress, &fatal, &path_template);
+            BOOK_ID.scope(book_id.clone(), async move {
+                if let Some(err) = &*fatal.lock().unwrap() {
+                    return (index, BookReport::skipped(book_id, err));
+                }
+                let started = Instant::now();
+                let bar = progress.start_book(book_id);
+                let result = run(client, book_id, cli_args, path_template, bar).await;
+                progress.finish_book(book_id);
+                let report = match result {
+                    Ok(downloaded) => {
+                        BookReport::downloaded(book_id, downloaded, started.elapsed())
+                    }
+                    Err(err) => {
+                        let report = BookReport::failed(book_id, &err, started.elapsed());
+                        match err {
+                            OrlyError::SessionExpired | OrlyError::Cancelled => {
+                                fatal.lock().unwrap().get_or_insert(err);
+                            }
+                            err => error!("{}", err),
+                        }
+                        report
+                    }
+                };
+                (index, report)
+            })
+        })
+        .buffer_unordered(cli_args.parallel_books)
+        .collect::<Vec<_>>()
+        .await;
+    reports.sort_by_key(|(index, _)| *index);
+    let reports = reports
+        .into_iter()
+        .map(|(_, report)| report)
+        .collect::<Vec<_>>();
+
+    progress.finish();
+    write_report(cli_args.report.as_deref(), &reports);
+
+    let skipped = reports
+        .iter()
+        .filter(|report| report.status == BookStatus::Skipped)
+        .count();
+    match fatal.into_inner().unwrap() {
+        Some(OrlyError::SessionExpired) => {
+            if skipped > 0 {
+                error!("Session expired, skipping the remaining {} books", skipped);
+            }
+            return Err(OrlyError::SessionExpired);
+        }
+        Some(err) => {
+            warn!("Cancelled, downloaded files are kept in the cache for the next run");
+            save_session(&client, session_file.as_deref());
+            return Err(err);
+        }
+        None => {}
+    }
+
+    // The server may have refreshed some of the cookies
+    save_session(&client, session_file.as_deref());
+
+    let failed = reports
+        .iter()
+        .filter(|report| matches!(report.status, BookStatus::Failed | BookStatus::Skipped))
+        .count();
+    if failed > 0 {
+        error!("Failed to download {} of {} books", failed, reports.len());
+        return Ok(ExitCode::from(failure_code(failed, reports.len())));
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Sign in with the first of the configured methods
+async fn authenticate(
+    client: OreillyClient<Unauthenticated>,
+    cli_args: &CliArgs,
+    session_file: Option<&Path>,
+) -> Result<OreillyClient<Authenticated>> {
+    // Sign in requests are not recorded, recorded responses don't depend on the session
+    if cli_args.replay.is_some() {
+        return client.cookies_auth(&[]).await;
+    }
+
+    let cookie_domain = client.endpoints().cookie_domain();
+
+    if let Some(cookie) = &cli_args.cookie {
+        client.cookie_auth(cookie).await
+    } else if let Some(cookie_file) = &cli_args.cookie_file {
         client
-            .cookie_auth(cli_args.cookie.as_ref().unwrap())
-            .await?
+            .cookies_auth(&cookies::load_cookie_file(cookie_file, &cookie_domain)?)
+            .await
+    } else if let Some(browser) = &cli_args.cookies_from_browser {
+        client
+            .cookies_auth(&cookies::load_browser_cookies(browser, &cookie_domain)?)
+            .await
+    } else {
+        let auth_host = client
+            .endpoints()
+            .auth_url
+            .host_str()
+            .unwrap_or_default()
+            .to_string();
+
+        if let (Some(session_file), None) = (session_file, &cli_args.creds) {
+            // The password is only asked for if the saved session can't be used
+            let creds = credentials(cli_args, &auth_host, false)?;
+            let creds = creds
+                .as_ref()
+                .map(|(email, password)| (email.as_str(), password.as_str()));
+            client
+                .session_auth(session_file, creds, || {
+                    credentials(cli_args, &auth_host, true)
+                })
+                .await
+        } else if let Some((email, password)) = credentials(cli_args, &auth_host, true)? {
+            // The saved session may belong to another account than the explicit credentials
+            client.cred_auth(&email, &password).await
+        } else {
+            Err(OrlyError::NoCredentials)
+        }
+    }
+}
+
+/// Email and password to sign in with. The email comes from `--creds`, `--email`, `ORLY_EMAIL`
+/// or the netrc entry of the auth host, and the password from `--creds`, `--password-file`,
+/// `ORLY_PASSWORD`, the netrc entry or, when `ask_password`, a prompt. Without `ask_password`
+/// an email without a password gives `None`.
+fn credentials(
+    cli_args: &CliArgs,
+    auth_host: &str,
+    ask_password: bool,
+) -> Result<Option<(String, String)>> {
+    if let Some(creds) = &cli_args.creds {
+        return Ok(Some((creds[0].clone(), creds[1].clone())));
+    }
+
+    let netrc = match default_netrc_path() {
+        Some(path) => netrc_entry(&path, auth_host)?,
+        None => None,
     };
+    let env = |name| std::env::var(name).ok();
 
-    for book_id in cli_args.book_ids.iter() {
-        if let Err(err) = run(&client, book_id, &cli_args.output, cli_args.kindle).await {
-            error!("{}", err)
+    let email = cli_args
+        .email
+        .clone()
+        .or_else(|| env("ORLY_EMAIL"))
+        .or_else(|| netrc.as_ref().and_then(|entry| entry.login.clone()));
+    let Some(email) = email else {
+        if cli_args.password_file.is_some() {
+            return Err(OrlyError::AuthenticationFailed(
+                "--password-file needs an email, use --email or ORLY_EMAIL".to_string(),
+            ));
         }
+        return Ok(None);
+    };
+    // The netrc password belongs to the netrc login
+    let netrc_password = netrc
+        .filter(|entry| entry.login.as_ref().map_or(true, |login| *login == email))
+        .and_then(|entry| entry.password);
+
+    let password = if let Some(password_file) = &cli_args.password_file {
+        read_password_file(password_file)?
+    } else if let Some(password) = env("ORLY_PASSWORD").or(netrc_password) {
+        password
+    } else if !ask_password {
+        return Ok(None);
+    } else if std::io::stdin().is_terminal() {
+        prompt_password(&email)?
+    } else {
+        return Err(OrlyError::AuthenticationFailed(format!(
+            "no password for {}, use --password-file or ORLY_PASSWORD",
+            email
+        )));
+    };
+
+    Ok(Some((email, password)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn saved_epub_is_not_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("book.epub");
+        save_epub(&output, b"epub").await.unwrap();
+
+        // Same permissions as any other new file, i.e. 0o666 minus the umask
+        let reference = dir.path().join("reference");
+        std::fs::write(&reference, b"").unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&output), mode(&reference));
+        assert_eq!(std::fs::read(&output).unwrap(), b"epub");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn directories_are_created_when_saving() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("Publisher/2024/book.epub");
+        save_epub(&output, b"epub").await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), b"epub");
+    }
+
+    #[test]
+    fn every_book_failed() {
+        assert_eq!(failure_code(3, 3), exit_code::FAILURE);
+        assert_eq!(failure_code(1, 1), exit_code::FAILURE);
+        assert_eq!(failure_code(1, 3), exit_code::PARTIAL_FAILURE);
+    }
+
+    #[test]
+    fn cancelled_books() {
+        let err = OrlyError::Cancelled;
+        let interrupted = BookReport::failed("1", &err, Duration::from_secs(1));
+        let not_attempted = BookReport::skipped("2", &err);
+        for report in [interrupted, not_attempted] {
+            assert_eq!(report.status, BookStatus::Cancelled);
+            let json = serde_json::to_value(&report).unwrap();
+            assert_eq!(json["status"], "cancelled");
+        }
+
+        let err = OrlyError::SessionExpired;
+        assert_eq!(
+            BookReport::failed("1", &err, Duration::ZERO).status,
+            BookStatus::Failed
+        );
+        assert_eq!(BookReport::skipped("2", &err).status, BookStatus::Skipped);
+    }
+
+    /// Parse `args` with a config file containing `config`
+    fn parse_with_config(config: &str, args: &[&str]) -> std::result::Result<CliArgs, clap::Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, config).unwrap();
+        let path = path.to_str().unwrap();
+        parse_args_from(["orly", "--config", path].iter().chain(args))
+    }
+
+    #[test]
+    fn config_defaults() {
+        let config = r#"
+            threads = 3
+            cookie = "session=1"
+            kindle = true
+
+            [profile.archive]
+            dir-template = "{publisher}"
+            threads = 5
+        "#;
+        let args = parse_with_config(config, &["1234"]).unwrap();
+        assert_eq!(args.threads, 3);
+        assert_eq!(args.cookie.as_deref(), Some("session=1"));
+        assert!(args.kindle);
+        assert_eq!(args.book_ids, ["1234"]);
+
+        let args = parse_with_config(config, &["--profile", "archive", "1234"]).unwrap();
+        assert_eq!(args.threads, 5);
+        assert_eq!(args.dir_template.as_deref(), Some("{publisher}"));
+        assert!(parse_with_config(config, &["--profile", "missing", "1234"]).is_err());
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let config = "threads = 3
+cookie = \"session=1\"\n";
+        let args = parse_with_config(config, &["--threads", "7", "1234"]).unwrap();
+        assert_eq!(args.threads, 7);
+
+        // Options conflicting with the command line ones are dropped
+        let args = parse_with_config(config, &["--creds", "email@example.com", "x", "1234"]);
+        let args = args.unwrap();
+        assert_eq!(args.cookie, None);
+        assert_eq!(args.creds.unwrap(), ["email@example.com", "x"]);
+    }
+
+    #[test]
+    fn unknown_config_options() {
+        // The config file can't select another config
+        for config in ["thread = 3", "config = \"other.toml\""] {
+            let err = parse_with_config(config, &["1234"]).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnknownArgument, "{}", config);
+        }
+    }
 }
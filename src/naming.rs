@@ -0,0 +1,355 @@
+use std::path::{Path, PathBuf};
+
+use sanitize_filename::sanitize;
+
+use crate::{
+    error::{OrlyError, Result},
+    models::Book,
+};
+
+/// Longest file or directory name most file systems accept, in bytes
+const MAX_COMPONENT_LEN: usize = 255;
+/// Longest path that can be opened without special prefixes, in bytes
+const MAX_PATH_LEN: usize = if cfg!(windows) { 259 } else { 4095 };
+/// Names are never truncated below this length to fit the path length limit
+const MIN_FILENAME_LEN: usize = 16;
+
+const PLACEHOLDERS: &[&str] = &[
+    "title",
+    "subtitle",
+    "authors",
+    "first_author",
+    "year",
+    "issued",
+    "isbn",
+    "id",
+    "publisher",
+    "language",
+];
+
+/// Location of a downloaded book relative to the output directory.
+///
+/// Both templates use `{placeholder}` syntax, see [`PLACEHOLDERS`] for the supported names.
+/// `/` in the directory template creates nested directories. Every substituted value is
+/// sanitized, so a `/` in a title never creates a directory. When a template uses `{subtitle}`,
+/// `{title}` is the part of the title before the first `:`.
+#[derive(Debug, Clone)]
+pub struct PathTemplate {
+    filename: String,
+    dir: String,
+    authors_separator: String,
+    max_authors: Option<usize>,
+}
+
+impl Default for PathTemplate {
+    fn default() -> Self {
+        Self {
+            filename: "{title} ({issued}) - {authors}".to_string(),
+            dir: String::new(),
+            authors_separator: ", ".to_string(),
+            max_authors: None,
+        }
+    }
+}
+
+impl PathTemplate {
+    pub fn with_filename(self, filename: &str) -> Result<Self> {
+        if filename.contains('/') {
+            return Err(OrlyError::InvalidTemplate(format!(
+                "{}: use the directory template to create directories",
+                filename
+            )));
+        }
+        validate(filename)?;
+        Ok(Self {
+            filename: filename.to_string(),
+            ..self
+        })
+    }
+
+    pub fn with_dir(self, dir: &str) -> Result<Self> {
+        validate(dir)?;
+        Ok(Self {
+            dir: dir.to_string(),
+            ..self
+        })
+    }
+
+    pub fn with_authors_separator(self, authors_separator: &str) -> Self {
+        Self {
+            authors_separator: authors_separator.to_string(),
+            ..self
+        }
+    }
+
+    /// Only list the first `max_authors` authors, followed by "et al."
+    pub fn with_max_authors(self, max_authors: usize) -> Self {
+        Self {
+            max_authors: Some(max_authors),
+            ..self
+        }
+    }
+
+    /// Whether the title is split into `{title}` and `{subtitle}` at the first `:`
+    fn splits_title(&self) -> bool {
+        self.filename.contains("{subtitle}") || self.dir.contains("{subtitle}")
+    }
+
+    fn value(&self, placeholder: &str, book: &Book, book_id: &str) -> String {
+        match placeholder {
+            "title" if self.splits_title() => book
+                .title
+                .split_once(':')
+                .map_or(book.title.as_str(), |(title, _)| title)
+                .trim()
+                .to_string(),
+            "title" => book.title.clone(),
+            "subtitle" => book
+                .title
+                .split_once(':')
+                .map(|(_, subtitle)| subtitle.trim().to_string())
+                .unwrap_or_default(),
+            "authors" => {
+                let max = self.max_authors.unwrap_or(usize::MAX);
+                let mut authors = book
+                    .authors
+                    .iter()
+                    .take(max)
+                    .map(|a| a.name.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(&self.authors_separator);
+                if book.authors.len() > max {
+                    authors.push_str(" et al.");
+                }
+                authors
+            }
+            "first_author" => book
+                .authors
+                .first()
+                .map(|a| a.name.clone())
+                .unwrap_or_default(),
+            "year" => book.issued.chars().take(4).collect(),
+            "issued" => book.issued.clone(),
+            "isbn" => book.isbn.clone(),
+            "id" => book_id.to_string(),
+            "publisher" => book
+                .publishers
+                .first()
+                .map(|p| p.name.clone())
+                .unwrap_or_default(),
+            "language" => book.language.clone(),
+            _ => unreachable!("placeholders are validated"),
+        }
+    }
+
+    /// Substitute the placeholders. Separators left dangling at either end by empty values are
+    /// removed, and so are trailing dots, which Windows does not allow.
+    fn render_component(&self, template: &str, book: &Book, book_id: &str) -> String {
+        let mut rendered = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = start + rest[start..].find('}').expect("placeholders are validated");
+            rendered.push_str(&rest[..start]);
+            rendered.push_str(&sanitize(self.value(&rest[start + 1..end], book, book_id)));
+            rest = &rest[end + 1..];
+        }
+        rendered.push_str(rest);
+
+        rendered
+            .trim_start_matches(|c: char| c.is_whitespace() || c == '-')
+            .trim_end_matches(|c: char| c.is_whitespace() || c == '-' || c == '.')
+            .to_string()
+    }
+
+    /// Path of the book relative to the output directory, `extension` included. The file name
+    /// is shortened to fit the path length limits when joined with `output_dir_len` bytes.
+    pub fn render(
+        &self,
+        book: &Book,
+        book_id: &str,
+        extension: &str,
+        output_dir_len: usize,
+    ) -> PathBuf {
+        let mut path = PathBuf::new();
+        for component in self.dir.split('/') {
+            let component = sanitize(self.render_component(component, book, book_id));
+            if !component.is_empty() {
+                path.push(truncate(&component, MAX_COMPONENT_LEN));
+            }
+        }
+
+        let mut filename = sanitize(self.render_component(&self.filename, book, book_id));
+        if filename.is_empty() {
+            filename = sanitize(book_id);
+        }
+        let used = output_dir_len + path.as_os_str().len() + extension.len() + 2;
+        let max_len = MAX_PATH_LEN
+            .saturating_sub(used)
+            .clamp(MIN_FILENAME_LEN, MAX_COMPONENT_LEN - extension.len() - 1);
+
+        path.push(format!("{}.{}", truncate(&filename, max_len), extension));
+        path
+    }
+}
+
+/// `path` with the first free ` (n)` suffix appended to the file name, for saving next to an
+/// existing file
+pub fn available_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    (1..)
+        .map(|n| {
+            let mut name = format!("{} ({})", stem, n);
+            if let Some(extension) = path.extension() {
+                name.push('.');
+                name.push_str(&extension.to_string_lossy());
+            }
+            path.with_file_name(name)
+        })
+        .find(|path| !path.exists())
+        .expect("a free name")
+}
+
+/// Check that every placeholder is known and closed
+fn validate(template: &str) -> Result<()> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| OrlyError::InvalidTemplate(format!("{}: unclosed {{", template)))?;
+        let placeholder = &rest[start + 1..end];
+        if !PLACEHOLDERS.contains(&placeholder) {
+            return Err(OrlyError::InvalidTemplate(format!(
+                "{}: unknown placeholder {{{}}}, supported placeholders: {}",
+                template,
+                placeholder,
+                PLACEHOLDERS.join(", ")
+            )));
+        }
+        rest = &rest[end + 1..];
+    }
+    Ok(())
+}
+
+/// Cut to at most `max_len` bytes on a char boundary
+fn truncate(name: &str, max_len: usize) -> &str {
+    if name.len() <= max_len {
+        return name;
+    }
+    let mut end = max_len;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name[..end].trim_end()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(title: &str, authors: &[&str], publisher: &str) -> Book {
+        let authors = authors
+            .iter()
+            .map(|name| serde_json::json!({ "name": name }))
+            .collect::<Vec<_>>();
+        let json = serde_json::json!({
+            "identifier": "1234",
+            "isbn": "9781234567897",
+            "cover": "https://learning.oreilly.com/library/cover/1234/",
+            "chapter_list": "",
+            "toc": "",
+            "flat_toc": "",
+            "title": title,
+            "source": "",
+            "pagecount": 1,
+            "authors": authors,
+            "subjects": [],
+            "publishers": [{ "name": publisher }],
+            "description": "",
+            "issued": "2024-01-02",
+            "language": "en",
+        });
+        serde_json::from_str(&json.to_string()).unwrap()
+    }
+
+    fn render(template: PathTemplate, book: &Book) -> String {
+        template
+            .render(book, "1234", "epub", 0)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn default_template() {
+        let book = book("Mock: A Book", &["Jane Doe", "John Doe"], "O'Reilly");
+        assert_eq!(
+            render(PathTemplate::default(), &book),
+            "Mock A Book (2024-01-02) - Jane Doe, John Doe.epub"
+        );
+    }
+
+    #[test]
+    fn split_title() {
+        let book = book("Mock: A Book", &["Jane Doe"], "O'Reilly");
+        let template = PathTemplate::default()
+            .with_filename("{title} - {subtitle}")
+            .unwrap();
+        assert_eq!(render(template, &book), "Mock - A Book.epub");
+
+        let template = PathTemplate::default()
+            .with_dir("{title}")
+            .unwrap()
+            .with_filename("{subtitle}")
+            .unwrap();
+        assert_eq!(render(template, &book), "Mock/A Book.epub");
+
+        // Without a subtitle the title is used as is
+        let book = self::book("Mock", &["Jane Doe"], "O'Reilly");
+        let template = PathTemplate::default()
+            .with_filename("{title} - {subtitle}")
+            .unwrap();
+        assert_eq!(render(template, &book), "Mock.epub");
+    }
+
+    #[test]
+    fn dangling_separators() {
+        let book = book("Mock", &[], "");
+        let template = PathTemplate::default()
+            .with_dir("{publisher}/{year}")
+            .unwrap()
+            .with_filename("{first_author} - {title} - {subtitle}.")
+            .unwrap();
+        assert_eq!(render(template, &book), "2024/Mock.epub");
+    }
+
+    #[test]
+    fn max_authors() {
+        let book = book("Mock", &["A", "B", "C"], "");
+        let template = PathTemplate::default()
+            .with_filename("{authors}")
+            .unwrap()
+            .with_authors_separator(" & ")
+            .with_max_authors(2);
+        assert_eq!(render(template, &book), "A & B et al.epub");
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert!(PathTemplate::default().with_filename("{title").is_err());
+        assert!(PathTemplate::default().with_filename("{name}").is_err());
+        assert!(PathTemplate::default()
+            .with_filename("{year}/{title}")
+            .is_err());
+        assert!(PathTemplate::default().with_dir("{year}/{isbn}").is_ok());
+    }
+
+    #[test]
+    fn long_names_are_truncated() {
+        let title = "a".repeat(400);
+        let book = book(&title, &[], "");
+        let template = PathTemplate::default().with_filename("{title}").unwrap();
+        let rendered = render(template, &book);
+        assert_eq!(rendered.len(), MAX_COMPONENT_LEN);
+        assert!(rendered.ends_with("a.epub"));
+    }
+}
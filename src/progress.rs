@@ -0,0 +1,120 @@
+use std::{fmt, sync::Arc};
+
+/// Stage of a book download
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    Chapters,
+    Images,
+    Stylesheets,
+    StylesheetDependencies,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Phase::Chapters => "chapters",
+            Phase::Images => "images",
+            Phase::Stylesheets => "css",
+            Phase::StylesheetDependencies => "css dependencies",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Reported when a phase starts, with `done` set to 0, and after every downloaded file
+#[derive(Debug, Clone)]
+pub struct ProgressEvent {
+    /// Book id passed to the client or the identifier of the book being built
+    pub book_id: String,
+    pub phase: Phase,
+    /// Number of files downloaded so far
+    pub done: usize,
+    pub total: usize,
+    /// Number of bytes downloaded so far in this phase
+    pub bytes: u64,
+}
+
+/// Receives progress events. Called from the download tasks, so it should return quickly, e.g.
+/// by updating a progress bar or forwarding the event to a channel.
+pub type ProgressCallback = Arc<dyn Fn(&ProgressEvent) + Send + Sync>;
+
+/// Counts finished downloads of a single phase
+pub(crate) struct Tracker<'a> {
+    callback: Option<&'a ProgressCallback>,
+    event: std::sync::Mutex<ProgressEvent>,
+}
+
+impl<'a> Tracker<'a> {
+    /// Tracker that reports nothing
+    pub fn disabled() -> Self {
+        Self::start(None, "", Phase::Chapters, 0)
+    }
+
+    /// Start tracking the phase and report it
+    pub fn start(
+        callback: Option<&'a ProgressCallback>,
+        book_id: &str,
+        phase: Phase,
+        total: usize,
+    ) -> Self {
+        let event = ProgressEvent {
+            book_id: book_id.to_string(),
+            phase,
+            done: 0,
+            total,
+            bytes: 0,
+        };
+        if let Some(callback) = callback {
+            callback(&event);
+        }
+        Self {
+            callback,
+            event: std::sync::Mutex::new(event),
+        }
+    }
+
+    /// Record a downloaded file of `bytes` length
+    pub fn advance(&self, bytes: usize) {
+        if let Some(callback) = self.callback {
+            let mut event = self.event.lock().unwrap();
+            event.done += 1;
+            event.bytes += bytes as u64;
+            callback(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn events() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let callback: ProgressCallback = Arc::new(move |event: &ProgressEvent| {
+            assert_eq!(event.book_id, "1234");
+            let event = (event.phase, event.done, event.total, event.bytes);
+            recorded.lock().unwrap().push(event);
+        });
+
+        let images = Tracker::start(Some(&callback), "1234", Phase::Images, 2);
+        images.advance(10);
+        images.advance(5);
+        // Phases without files are reported as started and finished at once
+        Tracker::start(Some(&callback), "1234", Phase::Stylesheets, 0);
+        Tracker::disabled().advance(1);
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                (Phase::Images, 0, 2, 0),
+                (Phase::Images, 1, 2, 10),
+                (Phase::Images, 2, 2, 15),
+                (Phase::Stylesheets, 0, 0, 0),
+            ]
+        );
+    }
+}
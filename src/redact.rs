@@ -0,0 +1,195 @@
+use std::{borrow::Cow, fmt};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use reqwest::header::HeaderMap;
+
+const MASK: &str = "***";
+
+/// Headers that carry the session or the credentials
+const SECRET_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-csrftoken",
+];
+
+lazy_static! {
+    static ref EMAIL: Regex =
+        Regex::new(r"[A-Za-z0-9._%+-]+@((?:[A-Za-z0-9-]+\.)+[A-Za-z]{2,})").unwrap();
+    static ref AUTH_SCHEME: Regex =
+        Regex::new(r"(?i)\b(basic|bearer)\s+[A-Za-z0-9+/=._~-]+").unwrap();
+    // `name: value` and `name=value` pairs of json, debug output, headers, urls and cookies
+    static ref SECRET_FIELD: Regex = Regex::new(
+        r#"(?i)(["']?\b(?:password|passwd|secret|[a-z_-]*token|jwt|orm-rt|[a-z_-]*sessionid|session_id|cookie|set-cookie|authorization)\b["']?\s*[:=]\s*)((?:(?:basic|bearer)\s+)?(?:"[^"]*"|'[^']*'|[^\s,;&})\]]+))"#
+    )
+    .unwrap();
+}
+
+/// Mask emails, passwords, tokens, cookies and authorization headers in a log message
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let text = match SECRET_FIELD.replace_all(text, |caps: &regex::Captures| {
+        let value = &caps[2];
+        if matches!(value, "None" | "null" | "\"\"" | "''") {
+            return caps[0].to_string();
+        }
+        let quote = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => quote.to_string(),
+            _ => String::new(),
+        };
+        format!("{}{}{}{}", &caps[1], quote, MASK, quote)
+    }) {
+        Cow::Borrowed(_) => Cow::Borrowed(text),
+        Cow::Owned(text) => Cow::Owned(text),
+    };
+    let text = match AUTH_SCHEME.replace_all(&text, format!("$1 {}", MASK)) {
+        Cow::Borrowed(_) => text,
+        Cow::Owned(redacted) => Cow::Owned(redacted),
+    };
+    match EMAIL.replace_all(&text, format!("{}@$1", MASK)) {
+        Cow::Borrowed(_) => text,
+        Cow::Owned(redacted) => Cow::Owned(redacted),
+    }
+}
+
+/// Output of a value with [`redact`] applied, for logging responses, urls and models
+pub(crate) struct Redacted<T>(pub T);
+
+impl<T: fmt::Debug> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = if f.alternate() {
+            format!("{:#?}", self.0)
+        } else {
+            format!("{:?}", self.0)
+        };
+        f.write_str(&redact(&text))
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&redact(&self.0.to_string()))
+    }
+}
+
+/// Debug output of headers with the values of [`SECRET_HEADERS`] masked
+pub(crate) struct RedactedHeaders<'a>(pub &'a HeaderMap);
+
+impl fmt::Debug for RedactedHeaders<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(name, value)| {
+                let value = if SECRET_HEADERS.contains(&name.as_str()) {
+                    MASK
+                } else {
+                    value.to_str().unwrap_or("<binary>")
+                };
+                (name.as_str(), value)
+            }))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::{HeaderValue, COOKIE, SET_COOKIE};
+
+    use super::*;
+
+    #[test]
+    fn login_body() {
+        assert_eq!(
+            redact(r#"{"email": "jane.doe@example.com", "password": "hunter2"}"#),
+            r#"{"email": "***@example.com", "password": "***"}"#
+        );
+        // Debug output of the request map
+        assert_eq!(
+            redact(r#"{"email": "jane@example.com", "password": "p@ss word"}"#),
+            r#"{"email": "***@example.com", "password": "***"}"#
+        );
+    }
+
+    #[test]
+    fn password_fields() {
+        assert_eq!(
+            redact("Credentials { password: \"hunter2\" }"),
+            "Credentials { password: \"***\" }"
+        );
+        assert_eq!(
+            redact("https://example.com/login?user=me&password=hunter2&next=/"),
+            "https://example.com/login?user=me&password=***&next=/"
+        );
+        assert_eq!(redact("passwd='hunter2'"), "passwd='***'");
+        assert_eq!(redact("password: None"), "password: None");
+        assert_eq!(redact("no secrets here"), "no secrets here");
+        assert!(matches!(redact("no secrets here"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn tokens() {
+        assert_eq!(
+            redact(r#"{"access_token": "abc", "refresh_token": "def", "logged_in": true}"#),
+            r#"{"access_token": "***", "refresh_token": "***", "logged_in": true}"#
+        );
+        assert_eq!(
+            redact("Authorization: Bearer abc.def-ghi"),
+            "Authorization: ***"
+        );
+        assert_eq!(redact("sent Basic dXNlcjpwYXNz"), "sent Basic ***");
+    }
+
+    #[test]
+    fn cookie_headers() {
+        assert_eq!(
+            redact("Cookie: orm-jwt=abc; groot_sessionid=def"),
+            "Cookie: ***; groot_sessionid=***"
+        );
+        assert_eq!(
+            redact("set-cookie: orm-rt=abc; Path=/; HttpOnly"),
+            "set-cookie: ***; Path=/; HttpOnly"
+        );
+    }
+
+    #[test]
+    fn header_maps() {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static("orm-jwt=abc"));
+        headers.insert(SET_COOKIE, HeaderValue::from_static("orm-rt=def; Path=/"));
+        headers.insert("x-csrftoken", HeaderValue::from_static("ghi"));
+        headers.insert("content-type", HeaderValue::from_static("text/html"));
+
+        let debug = format!("{:?}", RedactedHeaders(&headers));
+        for secret in ["abc", "def", "ghi"] {
+            assert!(!debug.contains(secret), "{}", debug);
+        }
+        assert!(
+            debug.contains(r#""content-type": "text/html""#),
+            "{}",
+            debug
+        );
+    }
+
+    #[test]
+    fn redacted_values() {
+        #[derive(Debug)]
+        #[allow(dead_code)]
+        struct Login<'a> {
+            email: &'a str,
+            password: &'a str,
+        }
+        let login = Login {
+            email: "jane@example.com",
+            password: "hunter2",
+        };
+        assert_eq!(
+            format!("{:?}", Redacted(&login)),
+            r#"Login { email: "***@example.com", password: "***" }"#
+        );
+        assert!(format!("{:#?}", Redacted(&login)).contains("\n    password: \"***\","));
+        assert_eq!(
+            Redacted("https://example.com/?token=abc").to_string(),
+            "https://example.com/?token=***"
+        );
+    }
+}
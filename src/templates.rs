@@ -28,6 +28,7 @@ pub struct ChapterXhtml<'a> {
     pub styles: &'a Vec<&'a String>,
     pub body: &'a str,
     pub should_support_kindle: bool,
+    pub epub3: bool,
 }
 
 #[derive(Template)]
@@ -45,7 +46,8 @@ pub struct NavPoint<'a> {
     pub order: usize,
     pub label: &'a str,
     pub url: String,
-    pub children: Vec<NavPoint<'a>>,
+    /// Rendered child navPoints
+    pub children: Vec<String>,
 }
 
 #[derive(Template)]
@@ -56,12 +58,34 @@ pub struct Toc<'a> {
     pub pagecount: usize,
     pub title: &'a str,
     pub author: &'a str,
-    pub navpoints: &'a Vec<NavPoint<'a>>,
+    /// Rendered navPoints
+    pub navpoints: &'a Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "navitem.xhtml", escape = "xml")]
+pub struct NavItem<'a> {
+    pub label: &'a str,
+    pub url: String,
+    /// Rendered child items
+    pub children: Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "nav.xhtml", escape = "xml")]
+pub struct Nav<'a> {
+    pub title: &'a str,
+    pub language: &'a str,
+    /// Rendered items
+    pub items: &'a Vec<String>,
+    /// epub:type, label and url of the landmarks
+    pub landmarks: &'a Vec<(&'a str, &'a str, String)>,
 }
 
 #[derive(Template)]
 #[template(path = "content.xml")]
 pub struct ContentOpf<'a> {
+    pub epub3: bool,
     pub title: &'a str,
     pub description: &'a str,
     pub publishers: &'a str,
@@ -69,11 +93,15 @@ pub struct ContentOpf<'a> {
     pub issued: &'a str,
     pub language: &'a str,
     pub isbn: &'a str,
+    pub identifier: &'a str,
+    pub fingerprint: &'a str,
+    pub modified: &'a str,
     pub cover_image: &'a str,
     pub authors: &'a Vec<Author>,
     pub subjects: &'a Vec<Subject>,
     pub styles: &'a Vec<&'a String>,
     pub css_deps: &'a Vec<&'a String>,
-    pub chapters: &'a Vec<String>,
+    /// File name and the manifest properties of the chapters
+    pub chapters: &'a Vec<(String, String)>,
     pub images: &'a Vec<(String, String)>,
 }
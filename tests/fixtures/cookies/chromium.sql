@@ -0,0 +1,28 @@
+-- Cookies database of a Chromium profile signed in to O'Reilly
+CREATE TABLE cookies (
+    creation_utc INTEGER NOT NULL,
+    host_key TEXT NOT NULL,
+    top_frame_site_key TEXT NOT NULL,
+    name TEXT NOT NULL,
+    value TEXT NOT NULL,
+    encrypted_value BLOB NOT NULL,
+    path TEXT NOT NULL,
+    expires_utc INTEGER NOT NULL,
+    is_secure INTEGER NOT NULL,
+    is_httponly INTEGER NOT NULL,
+    last_access_utc INTEGER NOT NULL,
+    has_expires INTEGER NOT NULL,
+    is_persistent INTEGER NOT NULL,
+    priority INTEGER NOT NULL,
+    samesite INTEGER NOT NULL,
+    source_scheme INTEGER NOT NULL,
+    source_port INTEGER NOT NULL,
+    last_update_utc INTEGER NOT NULL
+);
+-- Timestamps are microseconds since 1601-01-01, 15746918400000000 is 2100-01-01
+INSERT INTO cookies VALUES
+    (13340000000000000, '.oreilly.com', '', 'orm-jwt', 'jwt', x'', '/', 15746918400000000, 1, 1, 13340000000000000, 1, 1, 1, 0, 2, 443, 13340000000000000),
+    (13340000000000000, 'learning.oreilly.com', '', 'sessionid', 'session', x'', '/', 0, 1, 1, 13340000000000000, 0, 0, 1, 0, 2, 443, 13340000000000000),
+    -- Encrypted values can't be read
+    (13340000000000000, '.oreilly.com', '', 'encrypted', '', x'763130aabbcc', '/', 15746918400000000, 1, 1, 13340000000000000, 1, 1, 1, 0, 2, 443, 13340000000000000),
+    (13340000000000000, '.example.com', '', 'other', 'value', x'', '/', 15746918400000000, 0, 0, 13340000000000000, 1, 1, 1, 0, 2, 443, 13340000000000000);
@@ -0,0 +1,27 @@
+-- cookies.sqlite of a Firefox profile signed in to O'Reilly
+CREATE TABLE moz_cookies (
+    id INTEGER PRIMARY KEY,
+    originAttributes TEXT NOT NULL DEFAULT '',
+    name TEXT,
+    value TEXT,
+    host TEXT,
+    path TEXT,
+    expiry INTEGER,
+    lastAccessed INTEGER,
+    creationTime INTEGER,
+    isSecure INTEGER,
+    isHttpOnly INTEGER,
+    inBrowserElement INTEGER DEFAULT 0,
+    sameSite INTEGER DEFAULT 0,
+    rawSameSite INTEGER DEFAULT 0,
+    schemeMap INTEGER DEFAULT 0,
+    CONSTRAINT moz_uniqueid UNIQUE (name, host, path, originAttributes)
+);
+INSERT INTO moz_cookies (name, value, host, path, expiry, lastAccessed, creationTime, isSecure, isHttpOnly)
+VALUES
+    ('orm-jwt', 'jwt', '.oreilly.com', '/', 4102444800, 1700000000000000, 1700000000000000, 1, 1),
+    -- Newer versions store the expiration in milliseconds
+    ('orm-rt', 'refresh', '.oreilly.com', '/', 4102444800000, 1700000000000000, 1700000000000000, 1, 1),
+    ('sessionid', 'session', 'learning.oreilly.com', '/', 4102444800, 1700000000000000, 1700000000000000, 1, 1),
+    ('expired', 'old', '.oreilly.com', '/', 1000, 1700000000000000, 1700000000000000, 1, 0),
+    ('other', 'value', '.example.com', '/', 4102444800, 1700000000000000, 1700000000000000, 0, 0);
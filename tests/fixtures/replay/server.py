@@ -0,0 +1,184 @@
+"""Mock O'Reilly server the replay fixtures in 1234/ were recorded from.
+
+Regenerate the fixtures with:
+
+    python3 tests/fixtures/replay/server.py &
+    cargo run -- --learning-url http://127.0.0.1:8770/ --auth-url http://127.0.0.1:8770/ \
+        --creds jane@example.com hunter2 --no-session --no-cache \
+        --record tests/fixtures/replay/1234 -o "$(mktemp -d)" 1234
+"""
+
+import json
+import random
+import struct
+import sys
+import zlib
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+PORT = int(sys.argv[1]) if len(sys.argv) > 1 else 8770
+BASE = f"http://127.0.0.1:{PORT}"
+FILES = f"{BASE}/api/v2/epubs/urn:orm:book:1234/files/"
+
+
+def png(width, height, alpha=False, noise=False):
+    rnd = random.Random(42)
+    channels = 4 if alpha else 3
+
+    def row():
+        if noise:
+            return bytes(rnd.getrandbits(8) for _ in range(width * channels))
+        return b"\x80" * (width * channels)
+
+    def chunk(kind, data):
+        crc = zlib.crc32(kind + data) & 0xFFFFFFFF
+        return struct.pack(">I", len(data)) + kind + data + struct.pack(">I", crc)
+
+    raw = b"".join(b"\x00" + row() for _ in range(height))
+    header = struct.pack(">IIBBBBB", width, height, 8, 6 if alpha else 2, 0, 0, 0)
+    return (
+        b"\x89PNG\r\n\x1a\n"
+        + chunk(b"IHDR", header)
+        + chunk(b"IDAT", zlib.compress(raw))
+        + chunk(b"IEND", b"")
+    )
+
+
+# Large enough to be converted to jpeg
+BIG = png(150, 150, noise=True)
+SMALL = png(10, 10, alpha=True)
+
+
+def chapter(filename, title, images):
+    return {
+        "asset_base_url": FILES,
+        "title": title,
+        "filename": filename,
+        "images": images,
+        "stylesheets": [
+            {
+                "full_path": "style.css",
+                "url": f"{BASE}/css/style.css",
+                "original_url": "style.css",
+            }
+        ],
+        "site_styles": [],
+        "content": f"{BASE}/content/{filename}",
+    }
+
+
+def toc_entry(depth, filename, label, href, id, fragment="", children=()):
+    return {
+        "depth": depth,
+        "url": "",
+        "minutes_required": 1.0,
+        "fragment": fragment,
+        "filename": filename,
+        "natural_key": [],
+        "label": label,
+        "full_path": filename,
+        "href": href,
+        "id": id,
+        "media_type": "text/html",
+        "children": list(children),
+    }
+
+
+JSON = {
+    "/api/v1/": {"subscription": {}, "trial": {}},
+    "/api/v1/book/1234/": {
+        "identifier": "1234",
+        "isbn": "9781234567897",
+        "cover": f"{BASE}/cover.jpg",
+        "chapter_list": "",
+        "toc": "",
+        "flat_toc": "",
+        "title": "Mock: A Book/Test",
+        "source": "",
+        "pagecount": 10,
+        "authors": [{"name": "Jane Doe"}, {"name": "John Roe"}],
+        "subjects": [{"name": "Testing"}],
+        "publishers": [{"name": "Mock Media"}],
+        "description": "<p>A test book</p>",
+        "issued": "2024-01-02",
+        "rights": "",
+        "language": "en",
+    },
+    "/api/v1/book/1234/chapter": {
+        "count": 2,
+        "results": [
+            chapter("cover.html", "Cover", ["images/cover.png"]),
+            chapter("ch01.html", "Chapter 1", ["images/fig1.png", "images/cover.png"]),
+        ],
+    },
+    "/api/v1/book/1234/toc": [
+        toc_entry(1, "cover.html", "Cover", "cover.html", "cover"),
+        toc_entry(
+            1,
+            "ch01.html",
+            "Chapter 1",
+            "ch01.html",
+            "ch01",
+            children=[
+                toc_entry(2, "ch01.html", "Section 1.1", "ch01.html#sec1", "ch01s1", "sec1")
+            ],
+        ),
+    ],
+}
+
+FILES_PATH = "/api/v2/epubs/urn:orm:book:1234/files/"
+RAW = {
+    "/content/cover.html": (
+        "text/html",
+        b'<html><body><div id="sbo-rt-content">'
+        b'<img src="images/cover.png" alt="cover"/>'
+        b"</div></body></html>",
+    ),
+    "/content/ch01.html": (
+        "text/html",
+        b'<html><body><div id="sbo-rt-content"><h1>Chapter 1</h1>'
+        b'<p>Hello <a href="cover.html">cover</a></p><h2 id="sec1">Section</h2>'
+        b'<img src="images/fig1.png" alt="fig"/></div></body></html>',
+    ),
+    "/css/style.css": (
+        "text/css",
+        b"body { font-family: serif; } .hidden { display: none; } "
+        b"@font-face { font-family: X; src: url(fonts/x.woff); } "
+        b'h1 { background: url("images/bg.png"); }',
+    ),
+    FILES_PATH + "images/cover.png": ("image/png", SMALL),
+    FILES_PATH + "images/fig1.png": ("image/png", BIG),
+    FILES_PATH + "fonts/x.woff": ("font/woff", b"wOFFfake"),
+    # Same as an image of a chapter, the stylesheet links the converted file
+    FILES_PATH + "images/bg.png": ("image/png", BIG),
+}
+
+
+class Handler(BaseHTTPRequestHandler):
+    def log_message(self, *args):
+        pass
+
+    def reply(self, code, content_type, body):
+        self.send_response(code)
+        self.send_header("Content-Type", content_type)
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def do_GET(self):
+        path = self.path.split("?")[0]
+        if path in JSON:
+            self.reply(200, "application/json", json.dumps(JSON[path]).encode())
+        elif path in RAW:
+            self.reply(200, *RAW[path])
+        else:
+            self.reply(404, "text/plain", b"not found")
+
+    def do_POST(self):
+        self.rfile.read(int(self.headers.get("Content-Length", 0)))
+        if "lookup" in self.path:
+            self.reply(200, "application/json", b'{"password_login_allowed": true}')
+        else:
+            self.reply(200, "application/json", b'{"logged_in": true}')
+
+
+ThreadingHTTPServer(("127.0.0.1", PORT), Handler).serve_forever()
@@ -0,0 +1,317 @@
+//! End-to-end tests against a run recorded with `--record` from the mock book served by
+//! `tests/fixtures/replay/server.py`
+
+use std::{
+    io::Cursor,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use orly::{
+    client::{Authenticated, OreillyClient},
+    endpoints::Endpoints,
+    epub::{
+        builder::{EpubBuilder, EpubVersion},
+        check::check,
+        fingerprint::{fingerprint, StoredMetadata},
+        images::{ImagePolicies, ImagePolicyRule},
+    },
+    error::OrlyError,
+    http::transport::Transport,
+    models::Chapter,
+    progress::{Phase, ProgressCallback, ProgressEvent},
+};
+
+const BOOK_ID: &str = "1234";
+
+async fn client() -> OreillyClient<Authenticated> {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/replay/1234");
+    let endpoints =
+        Endpoints::default().with_learning_url("http://127.0.0.1:8770/".parse().unwrap());
+
+    OreillyClient::default()
+        .with_endpoints(endpoints)
+        .with_transport(Transport::replay(fixtures).unwrap())
+        .cookies_auth(&[])
+        .await
+        .unwrap()
+}
+
+async fn generate(client: &OreillyClient<Authenticated>, version: EpubVersion) -> Vec<u8> {
+    let chapters = client.fetch_book_chapters(BOOK_ID).await.unwrap();
+    build(client, version, &chapters).await
+}
+
+async fn build(
+    client: &OreillyClient<Authenticated>,
+    version: EpubVersion,
+    chapters: &[Chapter],
+) -> Vec<u8> {
+    let book = client.fetch_book_details(BOOK_ID).await.unwrap();
+    let toc = client.fetch_toc(BOOK_ID).await.unwrap();
+
+    let mut buffer = Cursor::new(Vec::new());
+    EpubBuilder::new(&book, false, client.endpoints())
+        .unwrap()
+        .with_version(version)
+        .chapters(chapters)
+        .unwrap()
+        .toc(&toc)
+        .unwrap()
+        .generate(&mut buffer, client)
+        .await
+        .unwrap();
+    buffer.into_inner()
+}
+
+fn entry(epub: &[u8], name: &str) -> String {
+    let mut archive = zip::ZipArchive::new(Cursor::new(epub)).unwrap();
+    let mut content = String::new();
+    std::io::Read::read_to_string(&mut archive.by_name(name).unwrap(), &mut content).unwrap();
+    content
+}
+
+#[tokio::test]
+async fn book_details() {
+    let book = client().await.fetch_book_details(BOOK_ID).await.unwrap();
+
+    assert_eq!(book.identifier, BOOK_ID);
+    assert_eq!(book.title, "Mock: A Book/Test");
+    assert_eq!(book.isbn, "9781234567897");
+    assert_eq!(book.authors.len(), 2);
+    assert_eq!(book.issued, "2024-01-02");
+}
+
+#[tokio::test]
+async fn missing_book() {
+    let err = client().await.fetch_book_details("4321").await.unwrap_err();
+    assert!(
+        matches!(&err, OrlyError::NotFound { book_id: Some(id), .. } if id == "4321"),
+        "{:?}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn missing_chapter() {
+    let client = client().await;
+    let mut meta = client.fetch_book_chapters_meta(BOOK_ID).await.unwrap();
+    meta[1].content_url = meta[1].content_url.join("ch02.html").unwrap();
+
+    let err = client
+        .fetch_book_chapters_content(BOOK_ID, meta)
+        .await
+        .unwrap_err();
+    // Only a missing book is reported as such
+    assert!(
+        matches!(&err, OrlyError::NotFound { book_id: None, url } if url.ends_with("/ch02.html")),
+        "{:?}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn book_chapters() {
+    let chapters = client().await.fetch_book_chapters(BOOK_ID).await.unwrap();
+
+    let names = chapters
+        .iter()
+        .map(|chapter| chapter.meta.filename.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["cover.xhtml", "ch01.xhtml"]);
+    assert_eq!(chapters[1].meta.position, 1);
+    assert_eq!(
+        chapters[1].meta.images,
+        ["images/fig1.png", "images/cover.png"]
+    );
+    assert!(chapters[1].content.contains("<h2 id=\"sec1\">Section</h2>"));
+}
+
+#[tokio::test]
+async fn toc() {
+    let toc = client().await.fetch_toc(BOOK_ID).await.unwrap();
+
+    let labels = toc
+        .iter()
+        .map(|element| element.label.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(labels, ["Cover", "Chapter 1"]);
+    assert_eq!(toc[1].children[0].href, "ch01.xhtml#sec1");
+}
+
+#[tokio::test]
+async fn epub() {
+    let client = client().await;
+    let epub = generate(&client, EpubVersion::V2).await;
+
+    let report = check(&epub).unwrap();
+    assert!(report.issues.is_empty(), "{:?}", report.issues);
+
+    let opf = entry(&epub, "OEBPS/content.opf");
+    assert!(opf.contains("<dc:title>Mock: A Book/Test</dc:title>"));
+    assert!(opf.contains("opf:role=\"aut\">Jane Doe</dc:creator>"));
+
+    let chapter = entry(&epub, "OEBPS/Text/ch01.xhtml");
+    assert!(chapter.contains("<h2 id=\"sec1\">Section</h2>"));
+    assert!(chapter.contains("href=\"cover.xhtml\""));
+    // Images are downloaded and linked by their new name
+    assert!(chapter.contains("src=\"../Images/fig1-"));
+
+    let ncx = entry(&epub, "OEBPS/toc.ncx");
+    assert!(ncx.contains("Text/ch01.xhtml#sec1"));
+
+    // Builds are reproducible
+    assert_eq!(epub, generate(&client, EpubVersion::V2).await);
+}
+
+#[tokio::test]
+async fn progress() {
+    let client = client().await;
+    let book = client.fetch_book_details(BOOK_ID).await.unwrap();
+    let chapters = client.fetch_book_chapters(BOOK_ID).await.unwrap();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = events.clone();
+    let callback: ProgressCallback = Arc::new(move |event: &ProgressEvent| {
+        recorded.lock().unwrap().push(event.clone());
+    });
+
+    EpubBuilder::new(&book, false, client.endpoints())
+        .unwrap()
+        .with_progress(callback)
+        .chapters(&chapters)
+        .unwrap()
+        .generate(&mut Cursor::new(Vec::new()), &client)
+        .await
+        .unwrap();
+
+    // Every phase starts at zero and counts up to its total, one event per file
+    let events = events.lock().unwrap();
+    let mut phases = Vec::new();
+    for event in events.iter() {
+        assert_eq!(event.book_id, BOOK_ID);
+        if event.done == 0 {
+            phases.push((event.phase, event.total));
+        }
+        let (phase, total) = *phases.last().unwrap();
+        assert_eq!(event.phase, phase);
+        assert_eq!(event.total, total);
+    }
+    assert_eq!(
+        phases,
+        [
+            (Phase::Images, 2),
+            (Phase::Stylesheets, 1),
+            (Phase::StylesheetDependencies, 2),
+        ]
+    );
+    let done = events.iter().map(|event| event.done).collect::<Vec<_>>();
+    assert_eq!(done, [0, 1, 2, 0, 1, 0, 1, 2]);
+}
+
+#[tokio::test]
+async fn converted_images() {
+    let epub = generate(&client().await, EpubVersion::V2).await;
+
+    // Large pngs without transparency are converted to jpeg and renamed
+    let archive = zip::ZipArchive::new(Cursor::new(&epub)).unwrap();
+    let converted = archive
+        .file_names()
+        .find(|name| name.starts_with("OEBPS/Images/fig1-"))
+        .unwrap()
+        .trim_start_matches("OEBPS/")
+        .to_string();
+    assert!(converted.ends_with(".jpg"), "{}", converted);
+    assert!(entry(&epub, "OEBPS/content.opf")
+        .contains(&format!("href=\"{}\" media-type=\"image/jpeg\"", converted)));
+    assert!(entry(&epub, "OEBPS/Text/ch01.xhtml").contains(&format!("src=\"../{}\"", converted)));
+    // The stylesheet background is the same image
+    assert!(entry(&epub, "OEBPS/Styles/0.css").contains(&format!("url(\"../{}\")", converted)));
+}
+
+#[tokio::test]
+async fn image_links() {
+    let client = client().await;
+    let mut chapters = client.fetch_book_chapters(BOOK_ID).await.unwrap();
+    chapters[1]
+        .meta
+        .images
+        .push("images/missing.png".to_string());
+    chapters[1].content = chapters[1].content.replace(
+        "</div>",
+        r#"<img src="images/missing.png"/><img src="images/unknown.png"/></div>"#,
+    );
+    let epub = build(&client, EpubVersion::V2, &chapters).await;
+
+    let chapter = entry(&epub, "OEBPS/Text/ch01.xhtml");
+    assert!(chapter.contains("src=\"../Images/fig1-"));
+    // Listed, but missing on the server
+    assert!(chapter.contains("src=\"../Images/missing.png\""));
+    assert!(!chapter.contains("127.0.0.1"));
+    assert!(entry(&epub, "OEBPS/content.opf").contains("href=\"Images/missing.png\""));
+    let report = check(&epub).unwrap();
+    assert!(report.issues.is_empty(), "{:?}", report.issues);
+    // Not listed, kept as is
+    assert!(chapter.contains("src=\"images/unknown.png\""));
+}
+
+#[tokio::test]
+async fn epub3() {
+    let epub = generate(&client().await, EpubVersion::V3).await;
+
+    let report = check(&epub).unwrap();
+    assert!(report.issues.is_empty(), "{:?}", report.issues);
+    assert!(entry(&epub, "OEBPS/content.opf").contains("version=\"3.0\""));
+    assert!(entry(&epub, "OEBPS/nav.xhtml").contains("Section 1.1"));
+}
+
+#[tokio::test]
+async fn update_fingerprint() {
+    let client = client().await;
+    let book = client.fetch_book_details(BOOK_ID).await.unwrap();
+    let mut chapters = client.fetch_book_chapters(BOOK_ID).await.unwrap();
+    let policies = |rules: &[&str]| {
+        rules
+            .iter()
+            .map(|rule| rule.parse::<ImagePolicyRule>().unwrap())
+            .collect::<ImagePolicies>()
+    };
+    let current = fingerprint(&book, &chapters, false, EpubVersion::V2, &policies(&[]));
+
+    // The saved epub records what --if-exists update compares with
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("book.epub");
+    std::fs::write(&path, generate(&client, EpubVersion::V2).await).unwrap();
+    let stored = StoredMetadata::read(&path).unwrap();
+    assert_eq!(stored.book_id.as_deref(), Some(BOOK_ID));
+    assert_eq!(stored.fingerprint.as_deref(), Some(current.as_str()));
+
+    // Options that change the epub
+    for (kindle, version, rules) in [
+        (true, EpubVersion::V2, &[][..]),
+        (false, EpubVersion::V3, &[][..]),
+        (false, EpubVersion::V2, &["png=keep"][..]),
+    ] {
+        assert_ne!(
+            fingerprint(&book, &chapters, kindle, version, &policies(rules)),
+            current
+        );
+    }
+    // The default policy is the same as no policy
+    assert_eq!(
+        fingerprint(
+            &book,
+            &chapters,
+            false,
+            EpubVersion::V2,
+            &policies(&["png=auto"])
+        ),
+        current
+    );
+
+    // An edited chapter at the same url
+    chapters[1].content.push_str("<p>Erratum</p>");
+    assert_ne!(
+        fingerprint(&book, &chapters, false, EpubVersion::V2, &policies(&[])),
+        current
+    );
+}